@@ -10,7 +10,8 @@ use embassy_executor::{Spawner, InterruptExecutor};
 use embassy_rp::interrupt::{InterruptExt, Priority};
 use embassy_rp::interrupt;
 use lib::rlog::RemoteLog;
-use lib::stepper::{Motor, TurnSteps, Ticks, global_turn};
+use lib::stepper::Motor;
+use lib::sequence::{sequence_task, SequenceServer, SequenceUploads};
 use lib::net::{Wlan, WlanCredentials, WlanPins, Ipv4Config, Ipv4WithMask, Ipv4};
 use {defmt_rtt as _, panic_probe as _};
 
@@ -22,6 +23,18 @@ use {defmt_rtt as _, panic_probe as _};
 // use lib::rlog::{log, color::Color};
 
 static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
+static UPLOADS: SequenceUploads = SequenceUploads::new();
+
+const SEQUENCE: &str = "
+speed 1000
+accel 800
+repeat
+    move 2000
+    dwell 500
+    move -2000
+    dwell 500
+end
+";
 
 #[interrupt]
 unsafe fn SWI_IRQ_3() {
@@ -44,8 +57,10 @@ async fn main(spawner: Spawner) {
 
     let rl = RemoteLog::new(wlan.stack, 3333);
     let motor = Motor::new(Default::default(), p.PIN_4, p.PIN_3, p.PIN_5);
+    let sequences = SequenceServer::new(wlan.stack, 3334, &UPLOADS, motor.params.max_steps_per_second());
 
     unwrap!(spawner.spawn(rl.init()));
-    unwrap!(spawner_interrupt.spawn(global_turn(motor, TurnSteps(200 * 1000), Ticks(3))));
+    unwrap!(spawner.spawn(sequences.init()));
+    unwrap!(spawner_interrupt.spawn(sequence_task(motor, SEQUENCE, &UPLOADS)));
 }
 
@@ -0,0 +1,148 @@
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::stepper::{round, Axes, DigitalIn, DigitalOut, StateError, TrapezoidalProfile};
+
+use super::GCode;
+
+/// Runs parsed G-code on a set of axes, lengths are in millimeters and feeds in mm/min.
+pub struct Machine<P: DigitalOut, I: DigitalIn, const N: usize> {
+    pub axes: Axes<P, I, N>,
+    pub steps_per_mm: [f32; N],
+    /// Acceleration along the path in mm/s^2.
+    pub acceleration: f32,
+    pub rapid_feed: f32,
+    /// Feed of G1 moves in mm/min.
+    feed: f32,
+    relative: bool,
+    /// G20, lengths and feeds of the program are in inches.
+    inches: bool,
+}
+
+const MM_PER_INCH: f32 = 25.4;
+
+impl<P: DigitalOut, I: DigitalIn, const N: usize> Machine<P, I, N> {
+    pub fn new(axes: Axes<P, I, N>, steps_per_mm: [f32; N], acceleration: f32, rapid_feed: f32) -> Self {
+        Self {
+            axes,
+            steps_per_mm,
+            acceleration,
+            rapid_feed,
+            feed: rapid_feed,
+            relative: false,
+            inches: false,
+        }
+    }
+
+    /// Position of every axis in millimeters.
+    pub fn position(&self) -> [f32; N] {
+        let mut position = [0.0; N];
+        for ((mm, steps), per_mm) in position.iter_mut().zip(self.axes.position()).zip(self.steps_per_mm) {
+            *mm = steps as f32 / per_mm;
+        }
+        position
+    }
+
+    /// Puts the drivers whose hold time ran out to sleep, see `IdlePolicy::Hold`.
+    pub fn poll_idle(&mut self) {
+        self.axes.poll_idle()
+    }
+
+    /// Waits until every held driver went to sleep, `select` it against reading the next line.
+    pub async fn idle(&mut self) {
+        self.axes.idle().await
+    }
+
+    /// Runs one command, M114 answers with a `X:.. Y:.. Z:..` report line in millimeters.
+    pub async fn execute(&mut self, code: GCode) -> Result<Option<String<64>>, StateError> {
+        self.poll_idle();
+        match code {
+            GCode::Move { rapid, target, feed } => {
+                if let Some(feed) = feed {
+                    self.feed = self.to_mm(feed);
+                }
+                let feed = if rapid { self.rapid_feed } else { self.feed };
+                self.linear_move(target, feed).await?;
+            }
+            GCode::Home(axes) => {
+                for (motor, home) in self.axes.motors.iter_mut().zip(axes) {
+                    if home {
+                        motor.home().await?;
+                    }
+                }
+            }
+            GCode::Inches => self.inches = true,
+            GCode::Millimeters => self.inches = false,
+            GCode::Absolute => self.relative = false,
+            GCode::Relative => self.relative = true,
+            GCode::SetPosition(position) => {
+                for (axis, length) in position.into_iter().enumerate().take(N) {
+                    if let Some(length) = length {
+                        let steps = self.to_steps(axis, length);
+                        self.axes.motors[axis].set_position(steps);
+                    }
+                }
+            }
+            GCode::EnableMotors => self.axes.motors.iter_mut().for_each(|m| m.set_enabled(true)),
+            GCode::DisableMotors => self.axes.motors.iter_mut().for_each(|m| m.set_enabled(false)),
+            GCode::ReportPosition => return Ok(Some(self.report())),
+        }
+
+        Ok(None)
+    }
+
+    async fn linear_move(&mut self, target: [Option<f32>; 3], feed: f32) -> Result<(), StateError> {
+        let current = self.axes.position();
+        let mut steps = current;
+        for (axis, length) in target.into_iter().enumerate().take(N) {
+            if let Some(length) = length {
+                let offset = if self.relative { current[axis] } else { 0 };
+                steps[axis] = offset + self.to_steps(axis, length);
+            }
+        }
+
+        // the profile is in steps of the longest axis, scale the path feed and acceleration to it
+        let mut length = 0.0;
+        let mut major = 0;
+        for ((to, from), per_mm) in steps.iter().zip(current).zip(self.steps_per_mm) {
+            let delta = to - from;
+            let mm = delta as f32 / per_mm;
+            length += mm * mm;
+            major = major.max(delta.unsigned_abs());
+        }
+        if major == 0 {
+            return Ok(());
+        }
+
+        let scale = major as f32 / crate::stepper::sqrt(length);
+        let velocity = feed / 60.0 * scale;
+        let acceleration = self.acceleration * scale;
+        let profile = TrapezoidalProfile::new(velocity, acceleration, acceleration);
+
+        self.axes.move_to(steps, &profile).await?;
+        Ok(())
+    }
+
+    /// A length or feed of the program in millimeters.
+    fn to_mm(&self, length: f32) -> f32 {
+        if self.inches {
+            length * MM_PER_INCH
+        } else {
+            length
+        }
+    }
+
+    /// A length of the program in steps of `axis`.
+    fn to_steps(&self, axis: usize, length: f32) -> i32 {
+        round(self.to_mm(length) * self.steps_per_mm[axis])
+    }
+
+    fn report(&self) -> String<64> {
+        let mut report: String<64> = String::new();
+        for (axis, mm) in super::AXES.iter().zip(self.position()) {
+            let _ = write!(report, "{}:{:.2} ", axis, mm);
+        }
+        report
+    }
+}
@@ -0,0 +1,313 @@
+use core::str::FromStr;
+
+use heapless::Vec;
+
+mod machine;
+
+pub use machine::Machine;
+
+pub const AXES: [char; 3] = ['X', 'Y', 'Z'];
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum GCode {
+    /// G0 (`rapid`) and G1, `feed` in mm/min.
+    Move {
+        rapid: bool,
+        target: [Option<f32>; 3],
+        feed: Option<f32>,
+    },
+    /// G28, homes the listed axes, all of them when none is listed.
+    Home([bool; 3]),
+    /// G20, lengths and feeds in inches from here on.
+    Inches,
+    /// G21
+    Millimeters,
+    /// G90
+    Absolute,
+    /// G91
+    Relative,
+    /// G92
+    SetPosition([Option<f32>; 3]),
+    /// M17
+    EnableMotors,
+    /// M18
+    DisableMotors,
+    /// M114
+    ReportPosition,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ParseError {
+    MissingCommand,
+    UnknownCommand(char, u16),
+    BadWord(char),
+    /// A second command word on a line that already has one of its group, e.g. `G0 G1` or `G90 G91`.
+    ConflictingCommand(char, u16),
+}
+
+/// Commands of one line in the order they run: units, then distance mode, then the command itself.
+/// A line holds at most one word of each, e.g. `G21 G90 G1 X10`.
+pub type Block = Vec<GCode, 3>;
+
+/// Modal groups, the index of a command word's slot in a line.
+const UNITS: usize = 0;
+const DISTANCE: usize = 1;
+const COMMAND: usize = 2;
+
+/// Parses a single line, an empty block for lines that hold only comments, line numbers, `%` or whitespace.
+pub fn parse_line(line: &str) -> Result<Block, ParseError> {
+    let line = strip_comments(line);
+
+    let mut commands: [Option<(char, u16)>; 3] = [None; 3];
+    let mut words: [Option<f32>; 26] = [None; 26];
+    let mut flags = [false; 26];
+
+    let mut rest = line.trim_start();
+    while let Some(letter) = rest.chars().next() {
+        if letter == '(' {
+            rest = match rest.find(')') {
+                Some(end) => rest[end + 1..].trim_start(),
+                None => "",
+            };
+            continue;
+        }
+
+        let letter = letter.to_ascii_uppercase();
+        if !letter.is_ascii_alphabetic() {
+            return Err(ParseError::BadWord(letter));
+        }
+
+        rest = rest[1..].trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(end);
+        rest = tail.trim_start();
+
+        match letter {
+            'G' | 'M' => {
+                let code = u16::from_str(number).map_err(|_| ParseError::BadWord(letter))?;
+                let slot = &mut commands[group(letter, code)];
+                if slot.is_some() {
+                    return Err(ParseError::ConflictingCommand(letter, code));
+                }
+                *slot = Some((letter, code));
+            }
+            'N' => {}
+            _ if number.is_empty() => flags[index(letter)] = true,
+            _ => {
+                let value = f32::from_str(number).map_err(|_| ParseError::BadWord(letter))?;
+                words[index(letter)] = Some(value);
+            }
+        }
+    }
+
+    let mut block = Block::new();
+    for (letter, code) in commands.iter().flatten().copied() {
+        // one slot per group, so the block never overflows
+        let _ = block.push(command(letter, code, &words, &flags)?);
+    }
+    if commands[COMMAND].is_none() && words.iter().any(|w| w.is_some()) {
+        return Err(ParseError::MissingCommand);
+    }
+
+    Ok(block)
+}
+
+/// Which of a line's command slots a word takes.
+fn group(letter: char, code: u16) -> usize {
+    match (letter, code) {
+        ('G', 20) | ('G', 21) => UNITS,
+        ('G', 90) | ('G', 91) => DISTANCE,
+        _ => COMMAND,
+    }
+}
+
+fn command(letter: char, code: u16, words: &[Option<f32>; 26], flags: &[bool; 26]) -> Result<GCode, ParseError> {
+    let axes = AXES.map(|a| words[index(a)]);
+    let code = match (letter, code) {
+        ('G', 0) | ('G', 1) => GCode::Move {
+            rapid: code == 0,
+            target: axes,
+            feed: words[index('F')],
+        },
+        ('G', 20) => GCode::Inches,
+        ('G', 21) => GCode::Millimeters,
+        ('G', 28) => {
+            let listed = AXES.map(|a| flags[index(a)] || words[index(a)].is_some());
+            if listed.iter().any(|l| *l) {
+                GCode::Home(listed)
+            } else {
+                GCode::Home([true; 3])
+            }
+        }
+        ('G', 90) => GCode::Absolute,
+        ('G', 91) => GCode::Relative,
+        ('G', 92) => GCode::SetPosition(axes),
+        ('M', 17) => GCode::EnableMotors,
+        ('M', 18) | ('M', 84) => GCode::DisableMotors,
+        ('M', 114) => GCode::ReportPosition,
+        (letter, code) => return Err(ParseError::UnknownCommand(letter, code)),
+    };
+
+    Ok(code)
+}
+
+/// Cuts `;` comments and the `*checksum` suffix, `(...)` comments are skipped while parsing words.
+/// A `%` line marks the start or end of a program and holds nothing to run.
+fn strip_comments(line: &str) -> &str {
+    if line.trim_start().starts_with('%') {
+        return "";
+    }
+    match line.find(|c| c == ';' || c == '*') {
+        Some(end) => &line[..end],
+        None => line,
+    }
+}
+
+fn index(letter: char) -> usize {
+    (letter as u8 - b'A') as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<std::vec::Vec<GCode>, ParseError> {
+        parse_line(line).map(|block| block.iter().copied().collect())
+    }
+
+    fn program(text: &str) -> std::vec::Vec<GCode> {
+        text.lines()
+            .enumerate()
+            .flat_map(|(n, line)| parse_line(line).unwrap_or_else(|e| panic!("line {}: {:?}", n + 1, e)))
+            .collect()
+    }
+
+    fn to(x: Option<f32>, y: Option<f32>, z: Option<f32>, feed: Option<f32>) -> GCode {
+        GCode::Move {
+            rapid: false,
+            target: [x, y, z],
+            feed,
+        }
+    }
+
+    #[test]
+    fn parses_a_move() {
+        assert_eq!(
+            parse("G0 X1.5 Y-2 Z+.5 F1200"),
+            Ok(vec![GCode::Move {
+                rapid: true,
+                target: [Some(1.5), Some(-2.0), Some(0.5)],
+                feed: Some(1200.0),
+            }])
+        );
+        assert_eq!(parse("g1x3"), Ok(vec![to(Some(3.0), None, None, None)]));
+    }
+
+    #[test]
+    fn blank_and_comment_lines_are_empty() {
+        for line in ["", "   ", "; comment", "(comment)", "N10", "%", "  % program end", "*12"] {
+            assert_eq!(parse(line), Ok(vec![]), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn comments_and_checksums_are_skipped() {
+        assert_eq!(parse("N7 G1 (to the right) X2 ; edge"), Ok(vec![to(Some(2.0), None, None, None)]));
+        assert_eq!(parse("N7 G1 X2*85"), Ok(vec![to(Some(2.0), None, None, None)]));
+    }
+
+    #[test]
+    fn modal_words_run_before_the_command() {
+        assert_eq!(parse("G1 X10 G90"), Ok(vec![GCode::Absolute, to(Some(10.0), None, None, None)]));
+        assert_eq!(
+            parse("G91 G20 G0 Y1"),
+            Ok(vec![
+                GCode::Inches,
+                GCode::Relative,
+                GCode::Move {
+                    rapid: true,
+                    target: [None, Some(1.0), None],
+                    feed: None,
+                },
+            ])
+        );
+        assert_eq!(parse("G21"), Ok(vec![GCode::Millimeters]));
+    }
+
+    #[test]
+    fn two_words_of_a_group_conflict() {
+        assert_eq!(parse("G0 G1 X1"), Err(ParseError::ConflictingCommand('G', 1)));
+        assert_eq!(parse("G90 G91"), Err(ParseError::ConflictingCommand('G', 91)));
+        assert_eq!(parse("G20 G21"), Err(ParseError::ConflictingCommand('G', 21)));
+        assert_eq!(parse("G28 M114"), Err(ParseError::ConflictingCommand('M', 114)));
+    }
+
+    #[test]
+    fn home_lists_axes() {
+        assert_eq!(parse("G28"), Ok(vec![GCode::Home([true; 3])]));
+        assert_eq!(parse("G28 X Z"), Ok(vec![GCode::Home([true, false, true])]));
+        assert_eq!(parse("G28 Y0"), Ok(vec![GCode::Home([false, true, false])]));
+    }
+
+    #[test]
+    fn other_commands() {
+        assert_eq!(parse("G92 X0 Z5"), Ok(vec![GCode::SetPosition([Some(0.0), None, Some(5.0)])]));
+        assert_eq!(parse("M17"), Ok(vec![GCode::EnableMotors]));
+        assert_eq!(parse("M18"), Ok(vec![GCode::DisableMotors]));
+        assert_eq!(parse("M84"), Ok(vec![GCode::DisableMotors]));
+        assert_eq!(parse("M114"), Ok(vec![GCode::ReportPosition]));
+    }
+
+    #[test]
+    fn bad_lines_are_refused() {
+        assert_eq!(parse("X10"), Err(ParseError::MissingCommand));
+        assert_eq!(parse("G90 X10"), Err(ParseError::MissingCommand));
+        assert_eq!(parse("G2 X1"), Err(ParseError::UnknownCommand('G', 2)));
+        assert_eq!(parse("M104 S200"), Err(ParseError::UnknownCommand('M', 104)));
+        assert_eq!(parse("G1 X1..2"), Err(ParseError::BadWord('X')));
+        assert_eq!(parse("G X1"), Err(ParseError::BadWord('G')));
+        assert_eq!(parse("G1 #1"), Err(ParseError::BadWord('#')));
+    }
+
+    #[test]
+    fn square_program() {
+        let codes = program(include_str!("samples/square.gcode"));
+
+        assert_eq!(&codes[..2], &[GCode::Millimeters, GCode::Absolute]);
+        assert_eq!(codes[2], GCode::Home([true; 3]));
+        assert_eq!(codes[3], GCode::SetPosition([Some(0.0); 3]));
+        assert_eq!(
+            codes[5],
+            GCode::Move {
+                rapid: true,
+                target: [Some(-10.0), Some(-10.0), None],
+                feed: Some(3000.0)
+            }
+        );
+        assert_eq!(codes[6], to(Some(10.0), None, None, Some(600.0)));
+        assert_eq!(codes.len(), 13);
+        assert_eq!(codes[11], GCode::ReportPosition);
+        assert_eq!(codes[12], GCode::DisableMotors);
+    }
+
+    #[test]
+    fn relative_inch_program() {
+        let codes = program(include_str!("samples/relative_inches.gcode"));
+
+        assert_eq!(
+            codes,
+            vec![
+                GCode::Inches,
+                GCode::Relative,
+                to(Some(0.5), None, None, Some(10.0)),
+                to(None, Some(-0.25), None, None),
+                to(None, None, Some(0.1), None),
+                GCode::Millimeters,
+                GCode::Absolute,
+                GCode::DisableMotors,
+            ]
+        );
+    }
+}
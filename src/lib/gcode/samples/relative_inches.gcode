@@ -0,0 +1,7 @@
+; as a slicer writes it, line numbers and checksums included
+N1 G20*26
+N2 G91 G1 X0.5 F10*113
+N3 G1 Y-0.25*102
+N4 g1 z.1 (lift)*127
+N5 G90 G21*113
+N6 M84*25
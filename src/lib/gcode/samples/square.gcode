@@ -0,0 +1,15 @@
+%
+(20 mm square around the origin)
+G21 G90
+G28
+G92 X0 Y0 Z0
+M17
+G0 X-10 Y-10 F3000
+G1 X10 F600 ; bottom edge
+G1 Y10
+G1 X-10
+G1 Y-10
+G0 X0 Y0
+M114
+M18
+%
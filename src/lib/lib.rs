@@ -1,29 +1,45 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(type_alias_impl_trait)]
 #![feature(async_fn_in_trait)]
 #![allow(incomplete_features)]
 
+#[cfg(feature = "rp2040")]
 use embassy_net::Stack;
+#[cfg(feature = "rp2040")]
 use embassy_net_wiznet::Device;
-use embassy_rp::{bind_interrupts, usb::InterruptHandler as UsbInterruptHandler, peripherals::{USB, PIO0}, pio::InterruptHandler as PioInterruptHandler};
+#[cfg(feature = "rp2040")]
+use embassy_rp::{bind_interrupts, usb::InterruptHandler as UsbInterruptHandler, peripherals::{USB, PIO0, PIO1, UART0}, pio::InterruptHandler as PioInterruptHandler, uart::BufferedInterruptHandler};
 
 // CAN BE CALLED ONLY ONCE THRU ENTIRE PROGRAM
+#[cfg(feature = "rp2040")]
 bind_interrupts!(pub struct Irqs {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
+    PIO1_IRQ_0 => PioInterruptHandler<PIO1>;
     USBCTRL_IRQ => UsbInterruptHandler<USB>;
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
 });
 
 pub mod stepper;
 
+#[cfg(feature = "rp2040")]
+pub mod servo;
+
+pub mod gcode;
+
+pub mod sequence;
+
 // LOGGING THRU TCP, USE USB LOGGING WHEREVER YOU CAN
 pub mod rlog;
 
+#[cfg(feature = "rp2040")]
 pub mod ulog;
 
+#[cfg(feature = "rp2040")]
 pub mod net;
 
 // ----------
 // LIB SHARED
 // ----------
+#[cfg(feature = "rp2040")]
 pub type StackType = &'static Stack<Device<'static>>;
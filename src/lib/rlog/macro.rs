@@ -18,3 +18,10 @@ macro_rules! rerror {
         log($string, Color::Red)
     };
 }
+
+#[macro_export]
+macro_rules! rlogf {
+    ($($arg:tt)*) => {
+        log_fmt(format_args!($($arg)*), Color::Green)
+    };
+}
@@ -1,25 +1,38 @@
 pub mod color;
 pub mod r#macro;
 
-use core::fmt::{write, Debug};
+use core::fmt::{write, Arguments, Debug};
 
+use embassy_sync::{channel::Channel, blocking_mutex::raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+use heapless::String;
+
+// messages are queued anywhere, only the rp2040 build has a network to send them over
+#[cfg(feature = "rp2040")]
 use defmt::{warn, info};
+#[cfg(feature = "rp2040")]
 use embassy_executor::SpawnToken;
+#[cfg(feature = "rp2040")]
 use embassy_net::tcp::TcpSocket;
-use embassy_sync::{channel::Channel, blocking_mutex::raw::CriticalSectionRawMutex};
-use embassy_time::{Duration, Instant, with_timeout};
+#[cfg(feature = "rp2040")]
+use embassy_time::{Duration, with_timeout};
+#[cfg(feature = "rp2040")]
 use embedded_io_async::Write;
-use heapless::String;
 
+#[cfg(feature = "rp2040")]
 use crate::StackType;
 
 use self::color::Color;
 
 type ChanType<'a> = Channel<CriticalSectionRawMutex, Message<'a>, 4>;
 
+/// Longest formatted message `log_fmt` keeps, the rest is cut off.
+pub const MSG_LEN: usize = 64;
+
 
 static CHAN: ChanType = Channel::new();
 
+#[cfg(feature = "rp2040")]
 pub struct RemoteLog {
     stack: StackType,
     port: u16,
@@ -27,29 +40,43 @@ pub struct RemoteLog {
     tx_buffer: [u8; 4096],
 }
 
+enum Body<'a> {
+    Static(&'a (dyn Debug + Send + Sync)),
+    Owned(String<MSG_LEN>),
+}
+
 pub struct Message<'a> {
-    msg: &'a (dyn Debug + Send + Sync),
+    body: Body<'a>,
     color: Color,
 }
 
 impl<'a> Message<'a> {
     fn new(msg: &'a(dyn Debug + Send + Sync), color: Color) -> Self {
-        Message { msg, color }
+        Message { body: Body::Static(msg), color }
     }
 
-    fn fmt(&self) -> String<48> {
-        let mut buf: String<48> = String::new();
+    #[cfg_attr(not(feature = "rp2040"), allow(dead_code))]
+    fn fmt(&self) -> String<128> {
+        let mut buf: String<128> = String::new();
         let reset = Color::Reset;
-        write(&mut buf,
-            format_args!("{}{}{} {}s: {:?}\n",
-                self.color.make(), self.color.to_log_severity(), reset.make(), Instant::now().as_secs(), self.msg
-            )
-        ).unwrap();
+        match &self.body {
+            Body::Static(msg) => write(&mut buf,
+                format_args!("{}{}{} {}s: {:?}\n",
+                    self.color.make(), self.color.to_log_severity(), reset.make(), Instant::now().as_secs(), msg
+                )
+            ),
+            Body::Owned(msg) => write(&mut buf,
+                format_args!("{}{}{} {}s: {}\n",
+                    self.color.make(), self.color.to_log_severity(), reset.make(), Instant::now().as_secs(), msg
+                )
+            ),
+        }.unwrap();
 
         buf
     }
 }
 
+#[cfg(feature = "rp2040")]
 impl RemoteLog {
     pub fn new(stack: StackType, port: u16) -> Self {
         RemoteLog {
@@ -66,10 +93,18 @@ impl RemoteLog {
 }
 pub fn log(what: &'static (dyn Debug + Send + Sync), color: color::Color) -> () {
     let _ = CHAN.try_send(Message::new(what, color));
+}
+
+/// Same as `log` for messages built at runtime, formats right away so nothing has to outlive the call.
+pub fn log_fmt(args: Arguments, color: color::Color) -> () {
+    let mut msg: String<MSG_LEN> = String::new();
+    let _ = write(&mut msg, args);
+    let _ = CHAN.try_send(Message { body: Body::Owned(msg), color });
 }   
 
 
 
+#[cfg(feature = "rp2040")]
 #[embassy_executor::task]
 async fn _init(stack: StackType, port: u16, mut rx: [u8; 4096], mut tx: [u8; 4096]) -> () {
     loop {
@@ -0,0 +1,396 @@
+use core::str::FromStr;
+
+use heapless::Vec;
+
+mod runner;
+#[cfg(feature = "rp2040")]
+mod upload;
+
+pub use runner::{run, sequence_task, SequenceUploads};
+#[cfg(feature = "rp2040")]
+pub use upload::{SequenceServer, MAX_SCRIPT};
+
+/// Deepest `repeat` nesting a sequence may use.
+pub const MAX_DEPTH: usize = 4;
+/// Most ops `sequence_task` runs in one sequence.
+pub const MAX_OPS: usize = 64;
+
+/// One line of a sequence, all moves are in steps.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Op {
+    /// `turn <steps>`, in the current direction.
+    Turn(u32),
+    /// `move <steps>`, negative steps go backward.
+    MoveBy(i32),
+    /// `moveto <position>`
+    MoveTo(i32),
+    /// `dir fwd|back|toggle`
+    Dir(Dir),
+    /// `dwell <ms>`
+    Dwell(u32),
+    /// `speed <steps/s>`, moves after it run at this velocity, ramped when `accel` is set.
+    Speed(f32),
+    /// `ticks <n>`, moves after it run at a constant `Ticks(n)` instead. At least 2, a single tick
+    /// steps without ever sleeping and would starve every other task.
+    Ticks(u32),
+    /// `accel <steps/s^2>`, 0 turns the ramps off again.
+    Accel(f32),
+    /// `home`
+    Home,
+    /// `repeat [n]`, without a count it repeats forever. `end` is where its `End` is.
+    Repeat { count: u32, end: usize },
+    /// `end`, `start` is where its `Repeat` is.
+    End { start: usize },
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Dir {
+    Forward,
+    Backward,
+    Toggle,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ErrorKind {
+    UnknownOp,
+    MissingArgument,
+    BadArgument,
+    /// `end` without a `repeat`.
+    UnmatchedEnd,
+    /// `repeat` without an `end`.
+    UnclosedRepeat,
+    TooDeep,
+    TooLong,
+    /// `speed` above what the motor allows, see `Sequence::validate`.
+    TooFast,
+}
+
+/// `line` counts from 1, like editors do.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SequenceError {
+    pub line: u16,
+    pub kind: ErrorKind,
+}
+
+/// Parsed and checked sequence of at most `N` ops, loops already matched up.
+///
+/// The text format is one op per line, `#` starts a comment:
+/// ```text
+/// speed 800
+/// accel 400
+/// repeat 3
+///     move 1600
+///     dwell 250
+///     move -1600
+/// end
+/// ```
+#[derive(PartialEq, Clone, Debug)]
+pub struct Sequence<const N: usize> {
+    ops: Vec<Op, N>,
+    lines: Vec<u16, N>,
+}
+
+impl<const N: usize> Sequence<N> {
+    pub fn parse(text: &str) -> Result<Self, SequenceError> {
+        let mut ops = Vec::new();
+        let mut lines = Vec::new();
+        let mut open: Vec<usize, MAX_DEPTH> = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let number = (number + 1).min(u16::MAX as usize) as u16;
+            let error = |kind| SequenceError { line: number, kind };
+
+            let op = match parse_line(line).map_err(error)? {
+                Some(op) => op,
+                None => continue,
+            };
+
+            let op = match op {
+                Op::Repeat { count, .. } => {
+                    open.push(ops.len()).map_err(|_| error(ErrorKind::TooDeep))?;
+                    Op::Repeat { count, end: 0 }
+                }
+                Op::End { .. } => {
+                    let start = open.pop().ok_or_else(|| error(ErrorKind::UnmatchedEnd))?;
+                    let here = ops.len();
+                    if let Op::Repeat { end, .. } = &mut ops[start] {
+                        *end = here;
+                    }
+                    Op::End { start }
+                }
+                op => op,
+            };
+
+            ops.push(op).map_err(|_| error(ErrorKind::TooLong))?;
+            let _ = lines.push(number);
+        }
+
+        if let Some(start) = open.pop() {
+            return Err(SequenceError {
+                line: lines[start],
+                kind: ErrorKind::UnclosedRepeat,
+            });
+        }
+
+        Ok(Self { ops, lines })
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Checks every `speed` against `max_velocity` (steps per second), e.g. `MotorParams::max_steps_per_second`.
+    pub fn validate(&self, max_velocity: f32) -> Result<(), SequenceError> {
+        for (op, line) in self.ops.iter().zip(self.lines.iter()) {
+            if let Op::Speed(v) = op {
+                if *v > max_velocity {
+                    return Err(SequenceError {
+                        line: *line,
+                        kind: ErrorKind::TooFast,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Source line of the op at `index`, for reporting errors while running.
+    pub fn line(&self, index: usize) -> u16 {
+        self.lines.get(index).copied().unwrap_or(0)
+    }
+}
+
+/// `Ok(None)` for blank and comment-only lines.
+fn parse_line(line: &str) -> Result<Option<Op>, ErrorKind> {
+    let line = match line.find('#') {
+        Some(end) => &line[..end],
+        None => line,
+    };
+
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(ErrorKind::BadArgument);
+    }
+
+    let op = match_op(name, argument)?;
+    Ok(Some(op))
+}
+
+fn match_op(name: &str, argument: Option<&str>) -> Result<Op, ErrorKind> {
+    let is = |op: &str| name.eq_ignore_ascii_case(op);
+
+    let op = if is("turn") {
+        Op::Turn(number(argument)?)
+    } else if is("move") {
+        Op::MoveBy(number(argument)?)
+    } else if is("moveto") {
+        Op::MoveTo(number(argument)?)
+    } else if is("dir") {
+        let dir = argument.ok_or(ErrorKind::MissingArgument)?;
+        if dir.eq_ignore_ascii_case("fwd") || dir.eq_ignore_ascii_case("forward") {
+            Op::Dir(Dir::Forward)
+        } else if dir.eq_ignore_ascii_case("back") || dir.eq_ignore_ascii_case("backward") {
+            Op::Dir(Dir::Backward)
+        } else if dir.eq_ignore_ascii_case("toggle") {
+            Op::Dir(Dir::Toggle)
+        } else {
+            return Err(ErrorKind::BadArgument);
+        }
+    } else if is("dwell") {
+        Op::Dwell(number(argument)?)
+    } else if is("speed") {
+        Op::Speed(positive(number(argument)?)?)
+    } else if is("ticks") {
+        let ticks: u32 = number(argument)?;
+        if ticks < 2 {
+            return Err(ErrorKind::BadArgument);
+        }
+        Op::Ticks(ticks)
+    } else if is("accel") {
+        let accel: f32 = number(argument)?;
+        if accel < 0.0 || accel.is_nan() {
+            return Err(ErrorKind::BadArgument);
+        }
+        Op::Accel(accel)
+    } else if is("home") {
+        no_argument(argument)?;
+        Op::Home
+    } else if is("repeat") {
+        let count = match argument {
+            Some(_) => {
+                let count: u32 = number(argument)?;
+                if count == 0 {
+                    return Err(ErrorKind::BadArgument);
+                }
+                count
+            }
+            None => 0,
+        };
+        Op::Repeat { count, end: 0 }
+    } else if is("end") {
+        no_argument(argument)?;
+        Op::End { start: 0 }
+    } else {
+        return Err(ErrorKind::UnknownOp);
+    };
+
+    Ok(op)
+}
+
+fn number<T: FromStr>(argument: Option<&str>) -> Result<T, ErrorKind> {
+    let argument = argument.ok_or(ErrorKind::MissingArgument)?;
+    T::from_str(argument).map_err(|_| ErrorKind::BadArgument)
+}
+
+fn positive(value: f32) -> Result<f32, ErrorKind> {
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(ErrorKind::BadArgument)
+    }
+}
+
+fn no_argument(argument: Option<&str>) -> Result<(), ErrorKind> {
+    match argument {
+        Some(_) => Err(ErrorKind::BadArgument),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Sequence<16>, SequenceError> {
+        Sequence::parse(text)
+    }
+
+    fn error(text: &str) -> (u16, ErrorKind) {
+        let e = parse(text).unwrap_err();
+        (e.line, e.kind)
+    }
+
+    #[test]
+    fn parses_one_op_per_line() {
+        let sequence = parse("turn 10\nmove -20\nmoveto 5\ndir back\ndwell 250\nspeed 800\naccel 400\nhome").unwrap();
+        assert_eq!(
+            sequence.ops(),
+            [
+                Op::Turn(10),
+                Op::MoveBy(-20),
+                Op::MoveTo(5),
+                Op::Dir(Dir::Backward),
+                Op::Dwell(250),
+                Op::Speed(800.0),
+                Op::Accel(400.0),
+                Op::Home,
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let sequence = parse("# warm up\n\n  speed 800 # slow\n\t\nMOVE 10\n").unwrap();
+        assert_eq!(sequence.ops(), [Op::Speed(800.0), Op::MoveBy(10)]);
+        assert_eq!(sequence.line(0), 3);
+        assert_eq!(sequence.line(1), 5);
+    }
+
+    #[test]
+    fn empty_text_is_an_empty_sequence() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("# nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn matches_nested_loops() {
+        let sequence = parse("repeat 2\n  repeat\n    turn 1\n  end\nend").unwrap();
+        assert_eq!(
+            sequence.ops(),
+            [
+                Op::Repeat { count: 2, end: 4 },
+                Op::Repeat { count: 0, end: 3 },
+                Op::Turn(1),
+                Op::End { start: 1 },
+                Op::End { start: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn end_without_repeat() {
+        assert_eq!(error("turn 1\nend"), (2, ErrorKind::UnmatchedEnd));
+        assert_eq!(error("repeat 2\nturn 1\nend\nend"), (4, ErrorKind::UnmatchedEnd));
+    }
+
+    #[test]
+    fn repeat_without_end_points_at_the_repeat() {
+        assert_eq!(error("repeat 2\n  turn 1\n  repeat 3\n  end\n"), (1, ErrorKind::UnclosedRepeat));
+    }
+
+    #[test]
+    fn nesting_too_deep() {
+        assert!(parse("repeat\nrepeat\nrepeat\nrepeat\nturn 1\nend\nend\nend\nend").is_ok());
+        assert_eq!(error("repeat\nrepeat\nrepeat\nrepeat\nrepeat\n"), (5, ErrorKind::TooDeep));
+    }
+
+    #[test]
+    fn too_many_ops() {
+        let e = Sequence::<2>::parse("home\n# not an op\nhome\nhome").unwrap_err();
+        assert_eq!((e.line, e.kind), (4, ErrorKind::TooLong));
+    }
+
+    #[test]
+    fn unknown_op() {
+        assert_eq!(error("turn 1\njump 3"), (2, ErrorKind::UnknownOp));
+    }
+
+    #[test]
+    fn missing_arguments() {
+        assert_eq!(error("turn"), (1, ErrorKind::MissingArgument));
+        assert_eq!(error("dir # fwd"), (1, ErrorKind::MissingArgument));
+        assert_eq!(error("speed"), (1, ErrorKind::MissingArgument));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        for text in [
+            "turn -1", "turn x", "turn 1 2", "move 1.5", "dir up", "dwell -5", "speed 0", "speed -3", "speed nan",
+            "accel -1", "accel nan", "home 3", "end 1", "repeat 0", "repeat -1", "ticks 0", "ticks 1",
+        ] {
+            assert_eq!(error(text), (1, ErrorKind::BadArgument), "{}", text);
+        }
+    }
+
+    #[test]
+    fn ticks_sleep_at_least_once_per_step() {
+        assert_eq!(parse("ticks 2").unwrap().ops(), [Op::Ticks(2)]);
+    }
+
+    #[test]
+    fn validate_checks_every_speed() {
+        let sequence = parse("speed 500\nmove 10\nspeed 1500\nmove 10").unwrap();
+        assert_eq!(sequence.validate(2000.0), Ok(()));
+        assert_eq!(
+            sequence.validate(1000.0),
+            Err(SequenceError {
+                line: 3,
+                kind: ErrorKind::TooFast,
+            })
+        );
+    }
+
+    #[test]
+    fn validate_allows_the_limit_itself() {
+        assert_eq!(parse("speed 1000").unwrap().validate(1000.0), Ok(()));
+    }
+}
@@ -0,0 +1,167 @@
+use defmt::warn;
+use embassy_futures::select::{select, Either};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::stepper::{DigitalIn, DigitalOut, Direction, Motor, Profile, StateError, Ticks, TrapezoidalProfile, TurnSteps};
+
+use super::{Dir, Op, Sequence, MAX_DEPTH, MAX_OPS};
+
+/// Pace of moves before the sequence sets one of its own.
+const DEFAULT_TICKS: u32 = 3;
+
+/// Sequences waiting for `sequence_task`, meant to live in a `static`. Only the newest one is kept.
+pub struct SequenceUploads {
+    next: Signal<CriticalSectionRawMutex, Sequence<MAX_OPS>>,
+}
+
+impl SequenceUploads {
+    pub const fn new() -> Self {
+        Self { next: Signal::new() }
+    }
+
+    /// Replaces the sequence that's running once its current op is over.
+    pub fn load(&self, sequence: Sequence<MAX_OPS>) {
+        self.next.signal(sequence)
+    }
+}
+
+impl Default for SequenceUploads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `sequence` on `motor` from the top, returns the position it ended at.
+/// Moves go through `Motor::move_by` and friends, so travel limits and speed checks apply as usual.
+pub async fn run<P: DigitalOut, I: DigitalIn, const N: usize>(motor: &mut Motor<P, I>, sequence: &Sequence<N>) -> Result<i32, StateError> {
+    run_until(motor, sequence, || false).await
+}
+
+/// Same as `run`, but gives up before the next op once `cancelled` says so.
+async fn run_until<P: DigitalOut, I: DigitalIn, const N: usize>(
+    motor: &mut Motor<P, I>,
+    sequence: &Sequence<N>,
+    cancelled: impl Fn() -> bool,
+) -> Result<i32, StateError> {
+    let ops = sequence.ops();
+    // (index of the `Repeat`, passes left, 0 for forever)
+    let mut loops: Vec<(usize, u32), MAX_DEPTH> = Vec::new();
+    let mut pace = Pace::new();
+    let mut pc = 0;
+
+    while let Some(op) = ops.get(pc) {
+        if cancelled() {
+            break;
+        }
+        pc += 1;
+
+        match *op {
+            Op::Turn(steps) => motor.start_profiled(TurnSteps(steps), pace.profile()).await?,
+            Op::MoveBy(delta) => {
+                motor.move_by(delta, pace.profile()).await?;
+            }
+            Op::MoveTo(target) => {
+                motor.move_to(target, pace.profile()).await?;
+            }
+            Op::Dir(Dir::Forward) => motor.set_dir(Direction::Forward).await?,
+            Op::Dir(Dir::Backward) => motor.set_dir(Direction::Backward).await?,
+            Op::Dir(Dir::Toggle) => motor.toggle_dir().await?,
+            Op::Dwell(ms) => {
+                // a long dwell outlasts the hold time of the motor's idle policy
+                let until = Instant::now() + Duration::from_millis(ms as u64);
+                if let Either::Second(_) = select(Timer::at(until), motor.idle()).await {
+                    Timer::at(until).await;
+                }
+            }
+            Op::Speed(velocity) => pace.velocity = Some(velocity),
+            Op::Ticks(ticks) => {
+                pace.velocity = None;
+                pace.ticks = ticks;
+            }
+            Op::Accel(acceleration) => pace.acceleration = acceleration,
+            Op::Home => {
+                motor.home().await?;
+            }
+            Op::Repeat { count, .. } => {
+                // nesting was checked while parsing
+                let _ = loops.push((pc - 1, count));
+            }
+            Op::End { start } => {
+                // a loop of ops that never wait (`repeat` / `dir toggle` / `end`) mustn't hog the executor
+                yield_now().await;
+                if let Some((_, left)) = loops.last_mut() {
+                    if *left != 1 {
+                        *left = left.saturating_sub(1);
+                        pc = start + 1;
+                        continue;
+                    }
+                }
+                loops.pop();
+            }
+        }
+    }
+
+    Ok(motor.position())
+}
+
+/// How fast the moves of a sequence go, changed by `speed`, `accel` and `ticks` as it runs.
+struct Pace {
+    velocity: Option<f32>,
+    acceleration: f32,
+    ticks: u32,
+}
+
+impl Pace {
+    fn new() -> Self {
+        Self {
+            velocity: None,
+            acceleration: 0.0,
+            ticks: DEFAULT_TICKS,
+        }
+    }
+
+    fn profile(&self) -> Profile {
+        match self.velocity {
+            Some(v) if self.acceleration > 0.0 => TrapezoidalProfile::new(v, self.acceleration, self.acceleration).into(),
+            Some(v) => Profile::Velocity(v),
+            None => Ticks(self.ticks).into(),
+        }
+    }
+}
+
+/// Runs the sequence built into the firmware (none when `script` is empty), then whatever is uploaded
+/// to `uploads` (`SequenceServer`). An upload takes over from the running sequence after its current op.
+#[embassy_executor::task(pool_size = 4)]
+pub async fn sequence_task(mut motor: Motor, script: &'static str, uploads: &'static SequenceUploads) -> () {
+    let mut next = match Sequence::<MAX_OPS>::parse(script) {
+        Ok(sequence) if !sequence.is_empty() => Some(sequence),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Sequence doesn't parse: {:?}", defmt::Debug2Format(&e));
+            None
+        }
+    };
+
+    loop {
+        let sequence = match next.take() {
+            Some(sequence) => sequence,
+            // the motor goes to sleep as its idle policy says while nothing is uploaded
+            None => match select(uploads.next.wait(), motor.idle()).await {
+                Either::First(sequence) => sequence,
+                Either::Second(_) => uploads.next.wait().await,
+            },
+        };
+
+        if let Err(e) = sequence.validate(motor.params.max_steps_per_second()) {
+            warn!("Sequence is invalid: {:?}", defmt::Debug2Format(&e));
+            continue;
+        }
+        if let Err(e) = run_until(&mut motor, &sequence, || uploads.next.signaled()).await {
+            warn!("Sequence failed: {:?}", defmt::Debug2Format(&e));
+        }
+    }
+}
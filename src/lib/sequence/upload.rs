@@ -0,0 +1,105 @@
+use core::fmt::Write as _;
+
+use defmt::{info, warn};
+use embassy_executor::SpawnToken;
+use embassy_net::tcp::TcpSocket;
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+
+use crate::StackType;
+
+use super::{Sequence, SequenceUploads, MAX_OPS};
+
+/// Longest script a client may upload, in bytes.
+pub const MAX_SCRIPT: usize = 2048;
+
+/// Takes sequences over TCP for `sequence_task`, e.g. `nc -N 192.168.4.159 3334 < moves.txt`.
+///
+/// A client connects, sends the script and shuts down its side of the connection. It gets back `ok`
+/// or the first error, and only a script that parses and keeps to `max_velocity` is handed on.
+pub struct SequenceServer {
+    stack: StackType,
+    port: u16,
+    uploads: &'static SequenceUploads,
+    max_velocity: f32,
+}
+
+/// Why an upload wasn't taken, sent back to the client.
+enum Rejected {
+    Connection,
+    TooLong,
+    NotText,
+}
+
+impl SequenceServer {
+    /// `max_velocity` in steps per second, `MotorParams::max_steps_per_second` of the motor that runs them.
+    pub fn new(stack: StackType, port: u16, uploads: &'static SequenceUploads, max_velocity: f32) -> Self {
+        SequenceServer {
+            stack,
+            port,
+            uploads,
+            max_velocity,
+        }
+    }
+
+    pub fn init(&self) -> SpawnToken<impl Sized> {
+        _serve(self.stack, self.port, self.uploads, self.max_velocity)
+    }
+}
+
+#[embassy_executor::task]
+async fn _serve(stack: StackType, port: u16, uploads: &'static SequenceUploads, max_velocity: f32) -> () {
+    let mut rx = [0u8; 1024];
+    let mut tx = [0u8; 256];
+    let mut script: Vec<u8, MAX_SCRIPT> = Vec::new();
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        info!("Sequence upload available on port :{}", port);
+        if let Err(e) = socket.accept(port).await {
+            warn!("Error accepting tcp connection on port :{:?}, err: {:?}", port, e);
+            continue;
+        }
+
+        script.clear();
+        let mut reply: String<64> = String::new();
+        let _ = match receive(&mut socket, &mut script).await {
+            Ok(text) => match Sequence::<MAX_OPS>::parse(text).and_then(|s| s.validate(max_velocity).map(|_| s)) {
+                Ok(sequence) => {
+                    let ops = sequence.ops().len();
+                    uploads.load(sequence);
+                    writeln!(reply, "ok {} ops", ops)
+                }
+                Err(e) => writeln!(reply, "error line {}: {:?}", e.line, e.kind),
+            },
+            Err(Rejected::Connection) => {
+                socket.abort();
+                continue;
+            }
+            Err(Rejected::TooLong) => writeln!(reply, "error: longer than {} bytes", MAX_SCRIPT),
+            Err(Rejected::NotText) => writeln!(reply, "error: not utf-8"),
+        };
+
+        if socket.write_all(reply.as_bytes()).await.is_ok() {
+            let _ = socket.flush().await;
+        }
+        socket.close();
+    }
+}
+
+/// Reads until the client shuts down its side.
+async fn receive<'s>(socket: &mut TcpSocket<'_>, script: &'s mut Vec<u8, MAX_SCRIPT>) -> Result<&'s str, Rejected> {
+    let mut chunk = [0u8; 256];
+    loop {
+        let n = socket.read(&mut chunk).await.map_err(|_| Rejected::Connection)?;
+        if n == 0 {
+            break;
+        }
+        script.extend_from_slice(&chunk[..n]).map_err(|_| Rejected::TooLong)?;
+    }
+
+    core::str::from_utf8(script).map_err(|_| Rejected::NotText)
+}
@@ -0,0 +1,273 @@
+use defmt::warn;
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::pwm::{Channel, Config, Pwm, PwmPinA, PwmPinB};
+use embassy_rp::Peripheral;
+use embassy_time::{Duration, Ticker};
+
+use crate::stepper::{round, Command, Interrupt, MotionControl, Profile, StateError};
+
+/// 50 Hz, the frame rate hobby servos expect.
+const PERIOD_US: u16 = 20_000;
+const PERIOD: Duration = Duration::from_micros(PERIOD_US as u64);
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct ServoParams {
+    /// Pulse width at `min_angle`, in microseconds.
+    pub min_pulse: u16,
+    /// Pulse width at `max_angle`, in microseconds.
+    pub max_pulse: u16,
+    pub min_angle: f32,
+    pub max_angle: f32,
+    /// Degrees per second, 0 jumps straight to the target.
+    pub slew_rate: f32,
+    /// Degrees per step of a `Command`, so positions mean the same as for a stepper with `MotorParams::angle`.
+    pub step_angle: f32,
+}
+
+impl ServoParams {
+    pub fn new(min_pulse: u16, max_pulse: u16, min_angle: f32, max_angle: f32, slew_rate: f32) -> Self {
+        Self {
+            min_pulse,
+            max_pulse,
+            min_angle,
+            max_angle,
+            slew_rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_step_angle(mut self, step_angle: f32) -> Self {
+        self.step_angle = step_angle;
+        self
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.min_pulse < self.max_pulse
+            && self.max_pulse < PERIOD_US
+            && self.min_angle < self.max_angle
+            && self.slew_rate >= 0.0
+            && self.step_angle > 0.0
+    }
+
+    /// Worked out in floats and kept within the frame, so even params that aren't valid can't wrap around.
+    fn pulse(&self, angle: f32) -> u16 {
+        let span = self.max_pulse as f32 - self.min_pulse as f32;
+        let pulse = self.min_pulse as f32 + (angle - self.min_angle) / (self.max_angle - self.min_angle) * span + 0.5;
+        pulse.max(0.0).min((PERIOD_US - 1) as f32) as u16
+    }
+}
+
+impl Default for ServoParams {
+    fn default() -> Self {
+        Self {
+            min_pulse: 500,
+            max_pulse: 2500,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            slew_rate: 90.0,
+            step_angle: 1.0,
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum Output {
+    A,
+    B,
+}
+
+/// Hobby servo on one output of an RP2040 PWM slice, the other output of the slice stays free.
+pub struct Servo<'d, T: Channel> {
+    pub params: ServoParams,
+    pwm: Pwm<'d, T>,
+    config: Config,
+    output: Output,
+    angle: f32,
+    slew_rate: f32,
+    /// Way of the last move, a `Command::Turn` keeps going that way like a stepper keeps its dir pin.
+    backward: bool,
+    control: Option<&'static MotionControl>,
+}
+
+impl<'d, T: Channel> Servo<'d, T> {
+    /// Starts out centered, servos have no way to report where they are.
+    /// Refuses params that aren't `ServoParams::is_valid` with `StateError::InvalidProfile`, before any pulse goes out.
+    pub fn new_a(params: ServoParams, slice: impl Peripheral<P = T> + 'd, pin: impl Peripheral<P = impl PwmPinA<T>> + 'd) -> Result<Self, StateError> {
+        Self::check_params(&params)?;
+        let config = Self::frame_config();
+        let pwm = Pwm::new_output_a(slice, pin, config.clone());
+        Ok(Self::from_pwm(params, pwm, config, Output::A))
+    }
+
+    pub fn new_b(params: ServoParams, slice: impl Peripheral<P = T> + 'd, pin: impl Peripheral<P = impl PwmPinB<T>> + 'd) -> Result<Self, StateError> {
+        Self::check_params(&params)?;
+        let config = Self::frame_config();
+        let pwm = Pwm::new_output_b(slice, pin, config.clone());
+        Ok(Self::from_pwm(params, pwm, config, Output::B))
+    }
+
+    fn from_pwm(params: ServoParams, pwm: Pwm<'d, T>, config: Config, output: Output) -> Self {
+        let mut servo = Self {
+            params,
+            pwm,
+            config,
+            output,
+            angle: (params.min_angle + params.max_angle) / 2.0,
+            slew_rate: params.slew_rate,
+            backward: false,
+            control: None,
+        };
+        servo.write_pulse();
+        servo
+    }
+
+    fn check_params(params: &ServoParams) -> Result<(), StateError> {
+        if !params.is_valid() {
+            return Err(StateError::InvalidProfile);
+        }
+        Ok(())
+    }
+
+    /// One counter tick per microsecond, wrapping every 20 ms.
+    fn frame_config() -> Config {
+        let mut config = Config::default();
+        config.divider = ((clk_sys_freq() / 1_000_000) as u8).into();
+        config.top = PERIOD_US - 1;
+        config
+    }
+
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    /// Position in `Command` steps, see `ServoParams::step_angle`.
+    pub fn position(&self) -> i32 {
+        round(self.angle / self.params.step_angle)
+    }
+
+    /// Jumps to `angle` without slewing.
+    pub fn set_angle(&mut self, angle: f32) -> Result<(), StateError> {
+        self.check(angle)?;
+        if angle != self.angle {
+            self.backward = angle < self.angle;
+        }
+        self.angle = angle;
+        self.write_pulse();
+        Ok(())
+    }
+
+    /// Slews to `angle`, returns the angle the servo ended at (short of the target after a stop).
+    pub async fn move_to(&mut self, angle: f32) -> Result<f32, StateError> {
+        self.check(angle)?;
+        if angle != self.angle {
+            self.backward = angle < self.angle;
+        }
+
+        let mut target = angle;
+        let mut ticker = Ticker::every(PERIOD);
+
+        while self.angle != target {
+            self.take_interrupt(&mut target);
+
+            let step = self.slew_rate * PERIOD_US as f32 / 1_000_000.0;
+            self.angle = if step <= 0.0 {
+                target
+            } else if target > self.angle {
+                (self.angle + step).min(target)
+            } else {
+                (self.angle - step).max(target)
+            };
+            self.write_pulse();
+
+            ticker.next().await;
+        }
+
+        self.slew_rate = self.params.slew_rate;
+        Ok(self.angle)
+    }
+
+    pub async fn move_by(&mut self, degrees: f32) -> Result<f32, StateError> {
+        self.move_to(self.angle + degrees).await
+    }
+
+    /// Runs the commands of `control` like `stepper::motion_task` does for a motor, positions and speeds
+    /// are in `step_angle` steps. Spawn it from a task of your own, tasks can't be generic over the slice.
+    pub async fn run(&mut self, control: &'static MotionControl) -> ! {
+        self.control = Some(control);
+
+        loop {
+            // the slew rate has no limit of its own
+            let command = control.receive(f32::INFINITY).await;
+
+            let step_angle = self.params.step_angle;
+            let result = match command {
+                Command::Turn(turn_steps, profile) => {
+                    let degrees = turn_steps.0 as f32 * step_angle;
+                    let angle = if self.backward { self.angle - degrees } else { self.angle + degrees };
+                    self.command_move(angle, profile).await
+                }
+                Command::MoveTo(target, profile) => self.command_move(target as f32 * step_angle, profile).await,
+                Command::MoveBy(delta, profile) => self.command_move(self.angle + delta as f32 * step_angle, profile).await,
+                Command::Home => self.move_to((self.params.min_angle + self.params.max_angle) / 2.0).await,
+            };
+
+            if let Err(e) = result {
+                warn!("Servo command {:?} failed: {:?}", defmt::Debug2Format(&command), defmt::Debug2Format(&e));
+            }
+        }
+    }
+
+    /// Velocities of the profile (steps per second) override the slew rate for this move, ticks mean the default.
+    async fn command_move(&mut self, angle: f32, profile: Profile) -> Result<f32, StateError> {
+        if !profile.is_valid() {
+            return Err(StateError::InvalidProfile);
+        }
+
+        let velocity = match profile {
+            Profile::Constant(_) => None,
+            Profile::Velocity(v) => Some(v),
+            Profile::Trapezoidal(p) => Some(p.max_velocity),
+            Profile::SCurve(p) => Some(p.max_velocity),
+        };
+        if let Some(v) = velocity {
+            self.slew_rate = v * self.params.step_angle;
+        }
+
+        let result = self.move_to(angle).await;
+        self.slew_rate = self.params.slew_rate;
+        result
+    }
+
+    fn take_interrupt(&mut self, target: &mut f32) {
+        let interrupt = match self.control.and_then(|c| c.take_interrupt()) {
+            Some(interrupt) => interrupt,
+            None => return,
+        };
+
+        match interrupt {
+            // no inertia worth ramping down, both hold where the servo is
+            Interrupt::Stop | Interrupt::QuickStop => *target = self.angle,
+            // a slew rate of 0 would jump straight to the target
+            Interrupt::SetSpeed(v) if v > 0.0 => self.slew_rate = v * self.params.step_angle,
+            Interrupt::SetSpeed(_) => {}
+        }
+    }
+
+    /// `params` are public and may have been changed since the servo was built, so they're checked again.
+    fn check(&self, angle: f32) -> Result<(), StateError> {
+        Self::check_params(&self.params)?;
+        if angle < self.params.min_angle || angle > self.params.max_angle {
+            return Err(StateError::OutOfTravel);
+        }
+        Ok(())
+    }
+
+    fn write_pulse(&mut self) {
+        let pulse = self.params.pulse(self.angle);
+        match self.output {
+            Output::A => self.config.compare_a = pulse,
+            Output::B => self.config.compare_b = pulse,
+        }
+        self.pwm.set_config(&self.config);
+    }
+}
@@ -0,0 +1,242 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use super::backlash::take_up_all;
+use super::pin::{DigitalIn, DigitalOut};
+use super::ramp::{TrapezoidalProfile, TrapezoidalRamp};
+use super::{Direction, Interrupt, Motor, StateError, Ticks, TimingStats, TurnSteps};
+
+/// Moves several motors along a straight line, steps of the slower axes are interleaved with the
+/// axis that travels furthest (Bresenham), so all of them start and finish together.
+///
+/// Every step goes through the same checks as a single motor's move: a stop or speed change sent to the
+/// `MotionControl` of any motor applies to the whole line, each moving axis checks its end stop and encoder,
+/// and keeps its own `TimingStats` and `MotionEvent`s. A closed loop correction leaves that axis short of
+/// the line's end, the next move starts from the measured position and makes up for it.
+pub struct Axes<P: DigitalOut, I: DigitalIn, const N: usize> {
+    pub motors: [Motor<P, I>; N],
+}
+
+impl<P: DigitalOut, I: DigitalIn, const N: usize> Axes<P, I, N> {
+    pub fn new(motors: [Motor<P, I>; N]) -> Self {
+        Self { motors }
+    }
+
+    pub fn position(&self) -> [i32; N] {
+        let mut position = [0; N];
+        for (p, motor) in position.iter_mut().zip(self.motors.iter()) {
+            *p = motor.position();
+        }
+        position
+    }
+
+    pub async fn move_by(&mut self, deltas: [i32; N], profile: &TrapezoidalProfile) -> Result<[i32; N], StateError> {
+        let mut target = self.position();
+        for (t, d) in target.iter_mut().zip(deltas) {
+            *t += d;
+        }
+        self.move_to(target, profile).await
+    }
+
+    /// `profile` is in steps of the longest axis, returns the position every axis ended at.
+    pub async fn move_to(&mut self, target: [i32; N], profile: &TrapezoidalProfile) -> Result<[i32; N], StateError> {
+        self.run(target, profile, 0.0, 0.0).await
+    }
+
+    /// Puts every driver whose hold time ran out to sleep, see `Motor::poll_idle`.
+    pub fn poll_idle(&mut self) {
+        for motor in self.motors.iter_mut() {
+            motor.poll_idle();
+        }
+    }
+
+    /// Waits until every held driver went to sleep, for callers that wait between moves (e.g. for the next G-code line).
+    pub async fn idle(&mut self) {
+        while let Some(at) = self.motors.iter().filter_map(|m| m.sleep_deadline()).min() {
+            Timer::at(at).await;
+            self.poll_idle();
+        }
+    }
+
+    /// Same as `move_to` but entering and leaving at the given speeds (steps of the longest axis per second),
+    /// the drivers are kept awake when `exit` isn't zero (and otherwise left to their `IdlePolicy`) so the next segment continues without a gap.
+    pub(crate) async fn run(&mut self, target: [i32; N], profile: &TrapezoidalProfile, entry: f32, exit: f32) -> Result<[i32; N], StateError> {
+        if !profile.is_valid() {
+            return Err(StateError::InvalidProfile);
+        }
+
+        let mut steps = [0u32; N];
+        for (i, motor) in self.motors.iter_mut().enumerate() {
+            let delta = target[i] - motor.position();
+            if delta == 0 {
+                // an axis that sits the move out keeps its direction, and with it the end stop that counts
+                continue;
+            }
+            let direction = if delta < 0 { Direction::Backward } else { Direction::Forward };
+            motor.set_dir(direction).await?;
+
+            // shortening one axis would bend the line, so clamping limits refuse here too
+            steps[i] = delta.unsigned_abs();
+            if motor.limit_steps(TurnSteps(steps[i]))?.count() != steps[i] {
+                return Err(StateError::OutOfTravel);
+            }
+        }
+
+        let major = steps.iter().copied().max().unwrap_or(0);
+        if major == 0 {
+            return Ok(self.position());
+        }
+
+        // minor axes never step closer together than the major one, so the slowest moving motor sets the pace
+        let max_speed = self
+            .motors
+            .iter()
+            .zip(steps)
+            .filter(|(_, s)| *s > 0)
+            .map(|(m, _)| m.params.max_speed)
+            .max()
+            .unwrap_or(1);
+        let limit = 1_000_000.0 / max_speed as f32;
+        if profile.max_velocity > limit {
+            return Err(StateError::TooFast { requested: profile.max_velocity, limit });
+        }
+
+        let mut ramp = TrapezoidalRamp::with_speeds(*profile, major, entry, exit);
+
+        // axes that sit this move out mustn't keep holding past their time
+        self.poll_idle();
+
+        // wake every driver at once and settle for the slowest of them
+        let mut settle = Duration::from_micros(0);
+        for motor in self.motors.iter_mut() {
+            settle = settle.max(motor.wake_up());
+        }
+        if settle.as_micros() > 0 {
+            Timer::after(settle).await;
+        }
+
+        for (motor, s) in self.motors.iter_mut().zip(steps) {
+            motor.stats = TimingStats::new();
+            motor.begin_report(TurnSteps(s));
+        }
+
+        // play of all reversing axes is taken up together, one after another would stall the others mid-line
+        let result = match take_up_all(&mut self.motors, steps.map(|s| s > 0)).await {
+            Ok(()) => self.step_line(&mut ramp, steps, major, max_speed, limit).await,
+            Err(e) => Err(e),
+        };
+
+        if exit <= 0.0 || result.is_err() || self.stopped() {
+            for motor in self.motors.iter_mut() {
+                motor.release();
+            }
+        }
+        for (motor, s) in self.motors.iter_mut().zip(steps) {
+            if s > 0 {
+                motor.report_timing();
+                motor.publish_done(&result.map(|_| TurnSteps(0)));
+            }
+        }
+
+        result.map(|_| self.position())
+    }
+
+    /// Whether the last move was cut short by `MotionControl::stop` or `quick_stop`.
+    pub fn stopped(&self) -> bool {
+        self.motors.iter().any(|m| m.report.stopped)
+    }
+
+    /// Steps every axis along the line, `steps` per axis and `major` of the longest one.
+    async fn step_line(
+        &mut self,
+        ramp: &mut TrapezoidalRamp,
+        steps: [u32; N],
+        major: u32,
+        max_speed: u64,
+        limit: f32,
+    ) -> Result<(), StateError> {
+        let mut error = [major / 2; N];
+        let mut left = steps;
+        let mut deadline = Instant::now();
+        loop {
+            if self.take_interrupts(ramp, steps, major, limit) {
+                return Ok(());
+            }
+            for (motor, s) in self.motors.iter_mut().zip(steps) {
+                if s > 0 {
+                    motor.check_encoder(TurnSteps(0))?;
+                }
+            }
+
+            let interval = match ramp.next_interval() {
+                Some(interval) => interval.max(max_speed),
+                None => return Ok(()),
+            };
+
+            if self.motors.iter().zip(steps).any(|(m, s)| s > 0 && m.end_stop_hit()) {
+                return Err(StateError::EndStopTriggered);
+            }
+
+            for (i, motor) in self.motors.iter_mut().enumerate() {
+                error[i] += steps[i];
+                if error[i] >= major {
+                    error[i] -= major;
+                    motor.step_pin_ot.set_high();
+                    motor.stats.step(Instant::now());
+                    motor.position += motor.direction.sign();
+
+                    // the axis' own pace, for the velocity in its progress events
+                    left[i] = left[i].saturating_sub(1);
+                    motor.tick = Duration::from_micros(interval * major as u64 / steps[i] as u64);
+                    motor.publish_progress(TurnSteps(left[i]), Ticks(1));
+                }
+            }
+            for motor in self.motors.iter_mut() {
+                motor.step_pin_ot.set_low();
+            }
+
+            deadline += Duration::from_micros(interval);
+            Timer::at(deadline).await;
+            for motor in self.motors.iter_mut() {
+                motor.stats.woke(deadline, Duration::from_micros(0));
+            }
+        }
+    }
+
+    /// Applies what was sent to the `MotionControl` of any motor to the whole line, returns `true` when it ends at once.
+    /// Speeds are of the motor they were sent to, the line goes at the pace that gives that motor the speed.
+    /// One that would take the line past `limit` (steps of the longest axis per second, what the slowest motor allows)
+    /// is dropped, the same as a single motor drops a speed it can't do.
+    fn take_interrupts(&mut self, ramp: &mut TrapezoidalRamp, steps: [u32; N], major: u32, limit: f32) -> bool {
+        let mut stopped = false;
+        let mut quick = false;
+        for (motor, s) in self.motors.iter_mut().zip(steps) {
+            let interrupt = match motor.control {
+                Some(control) => control.take_interrupt(),
+                None => None,
+            };
+
+            match interrupt {
+                None => {}
+                Some(Interrupt::QuickStop) => quick = true,
+                Some(Interrupt::Stop) => stopped = true,
+                Some(Interrupt::SetSpeed(velocity)) if velocity > 0.0 && s > 0 => {
+                    let line = velocity * major as f32 / s as f32;
+                    if line <= limit {
+                        ramp.set_max_velocity(line);
+                    }
+                }
+                Some(Interrupt::SetSpeed(_)) => {}
+            }
+        }
+
+        if stopped {
+            ramp.stop();
+        }
+        if stopped || quick {
+            for motor in self.motors.iter_mut() {
+                motor.report.stopped = true;
+            }
+        }
+        quick
+    }
+}
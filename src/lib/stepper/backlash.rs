@@ -0,0 +1,108 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use super::consts;
+use super::pin::{DigitalIn, DigitalOut};
+use super::{Motor, StateError, TurnSteps};
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Steps of play (e.g. in a lead screw nut) taken up whenever a move goes the other way than the last one.
+    pub fn with_backlash(mut self, steps: u32) -> Self {
+        self.set_backlash(steps);
+        self
+    }
+
+    pub fn set_backlash(&mut self, steps: u32) {
+        self.backlash = steps;
+        self.owed = self.owed.min(steps);
+    }
+
+    pub fn backlash(&self) -> u32 {
+        self.backlash
+    }
+
+    /// Steps through the play before a move that reverses, without counting them into the position.
+    pub(crate) async fn take_up_backlash(&mut self) -> Result<(), StateError> {
+        take_up_all(core::array::from_mut(self), [true]).await
+    }
+
+    /// Steps of play the next move in the current direction has to take up first.
+    /// The first move after power-up only learns which side the play is on.
+    ///
+    /// `slack` is the side the play is being taken up towards and `owed` what's still missing to get there,
+    /// so a take-up cut short is finished by the next move that way, and one that turns back midway only
+    /// has to undo the steps it took.
+    fn play(&mut self) -> u32 {
+        match self.slack {
+            Some(side) if side != self.direction => self.owed = self.backlash - self.owed,
+            Some(_) => {}
+            None => self.owed = 0,
+        }
+        self.slack = Some(self.direction);
+
+        self.owed
+    }
+
+    /// Checks end stop and encoder and raises the step pin for one step of play, returns the play left after it.
+    fn play_step(&mut self, left: u32) -> Result<u32, StateError> {
+        if self.end_stop_hit() {
+            return Err(StateError::EndStopTriggered);
+        }
+
+        // a closed loop correction retakes the steps the shaft missed, the load didn't move either way
+        let position = self.position;
+        let left = self.check_encoder(TurnSteps(left))?.count();
+        if self.position != position {
+            self.position = position;
+            self.sync_encoder();
+        }
+        self.owed = left;
+        if left == 0 {
+            return Ok(0);
+        }
+
+        self.step_pin_ot.set_high();
+        self.stats.step(Instant::now());
+        // an encoder on the motor shaft sees the step, the load doesn't move
+        self.skip_encoder(self.direction.sign());
+        self.owed = left - 1;
+        Ok(self.owed)
+    }
+}
+
+/// Takes up the play of every motor in `moving` that reverses, all of them stepping together.
+/// A stop sent meanwhile cuts the take-up short and is left for the move to act on, the rest of the play stays owed.
+pub(crate) async fn take_up_all<P: DigitalOut, I: DigitalIn, const N: usize>(
+    motors: &mut [Motor<P, I>; N],
+    moving: [bool; N],
+) -> Result<(), StateError> {
+    let mut play = [0u32; N];
+    let mut max_speed: u64 = 0;
+    for (i, (motor, moving)) in motors.iter_mut().zip(moving).enumerate() {
+        if moving {
+            play[i] = motor.play();
+        }
+        if play[i] > 0 {
+            max_speed = max_speed.max(motor.params.max_speed);
+        }
+    }
+
+    let half = Duration::from_micros(max_speed * consts::BACKLASH_TICKS as u64 / 2);
+    while play.iter().any(|left| *left > 0) {
+        if motors.iter().any(|m| m.stop_requested()) {
+            return Ok(());
+        }
+
+        for (motor, left) in motors.iter_mut().zip(play.iter_mut()) {
+            if *left > 0 {
+                *left = motor.play_step(*left)?;
+            }
+        }
+        Timer::after(half).await;
+        for motor in motors.iter_mut() {
+            motor.step_pin_ot.set_low();
+        }
+        Timer::after(half).await;
+    }
+
+    Ok(())
+}
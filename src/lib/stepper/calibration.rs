@@ -0,0 +1,324 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use super::microstep::{Driver, Microsteps};
+use super::pin::{DigitalIn, DigitalOut};
+use super::{Motor, MotorParams};
+
+/// Last 4K sector of the 2 MB flash, far enough from the firmware (and any blobs placed after it).
+pub const DEFAULT_OFFSET: u32 = 0x1F_F000;
+
+/// "DRBC", tells a record apart from erased or foreign flash.
+const MAGIC: u32 = 0x4342_5244;
+/// Bump whenever the layout of `MotorCalibration` on flash changes, older records load as defaults.
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 20;
+const CRC_LEN: usize = 4;
+/// Whole record is written as one buffer, enough for 12 motors.
+const RECORD_LEN: usize = 256;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CalibrationError {
+    Flash,
+    /// No record at the offset, e.g. erased flash.
+    NotFound,
+    /// Record of an older or newer layout.
+    Version(u16),
+    Crc,
+    /// Record is for a different number of motors or holds values that don't decode.
+    Corrupt,
+    TooManyMotors,
+}
+
+/// What's kept of a motor across reboots.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub struct MotorCalibration {
+    pub params: MotorParams,
+    /// Position the home switch (or stop) is at, `home` sets the position to this instead of 0.
+    pub home_offset: i32,
+}
+
+impl MotorCalibration {
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&(self.params.spr as u32).to_le_bytes());
+        buf[4..8].copy_from_slice(&self.params.angle.to_bits().to_le_bytes());
+        buf[8] = match self.params.driver {
+            Driver::A4988 => 0,
+            Driver::Drv8825 => 1,
+            Driver::Tmc2209 => 2,
+        };
+        buf[9] = self.params.microsteps.factor().trailing_zeros() as u8;
+        buf[10..12].copy_from_slice(&[0, 0]);
+        buf[12..16].copy_from_slice(&(self.params.max_speed as u32).to_le_bytes());
+        buf[16..20].copy_from_slice(&self.home_offset.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let driver = match buf[8] {
+            0 => Driver::A4988,
+            1 => Driver::Drv8825,
+            2 => Driver::Tmc2209,
+            _ => return None,
+        };
+        let microsteps = match buf[9] {
+            0 => Microsteps::Full,
+            1 => Microsteps::Half,
+            2 => Microsteps::Quarter,
+            3 => Microsteps::Eighth,
+            4 => Microsteps::Sixteenth,
+            5 => Microsteps::ThirtySecond,
+            _ => return None,
+        };
+        if !microsteps.supported_by(driver) {
+            return None;
+        }
+
+        let mut params = MotorParams::new(read_u32(&buf[0..4]) as usize, f32::from_bits(read_u32(&buf[4..8])));
+        params.driver = driver;
+        params.microsteps = microsteps;
+        params.max_speed = read_u32(&buf[12..16]).max(1) as u64;
+
+        Some(Self {
+            params,
+            home_offset: read_u32(&buf[16..20]) as i32,
+        })
+    }
+}
+
+/// Calibration of `N` motors in one flash sector, erased and rewritten as a whole on every save.
+pub struct CalibrationStore<F: NorFlash> {
+    flash: F,
+    offset: u32,
+}
+
+impl<F: NorFlash> CalibrationStore<F> {
+    /// `offset` is counted from the start of flash and has to be sector aligned.
+    pub fn new(flash: F, offset: u32) -> Self {
+        Self { flash, offset }
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    pub fn load<const N: usize>(&mut self) -> Result<[MotorCalibration; N], CalibrationError> {
+        let len = record_len(N)?;
+        let mut buf = [0u8; RECORD_LEN];
+        self.flash.read(self.offset, &mut buf[..len]).map_err(|_| CalibrationError::Flash)?;
+
+        if read_u32(&buf[0..4]) != MAGIC {
+            return Err(CalibrationError::NotFound);
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version != VERSION {
+            return Err(CalibrationError::Version(version));
+        }
+        if crc32(&buf[..len - CRC_LEN]) != read_u32(&buf[len - CRC_LEN..len]) {
+            return Err(CalibrationError::Crc);
+        }
+        if u16::from_le_bytes([buf[6], buf[7]]) as usize != N {
+            return Err(CalibrationError::Corrupt);
+        }
+
+        let mut motors = [MotorCalibration::default(); N];
+        for (i, motor) in motors.iter_mut().enumerate() {
+            let start = HEADER_LEN + i * ENTRY_LEN;
+            *motor = MotorCalibration::decode(&buf[start..start + ENTRY_LEN]).ok_or(CalibrationError::Corrupt)?;
+        }
+        Ok(motors)
+    }
+
+    /// Same as `load`, but anything unreadable comes back as defaults.
+    pub fn load_or_default<const N: usize>(&mut self) -> [MotorCalibration; N] {
+        self.load().unwrap_or([MotorCalibration::default(); N])
+    }
+
+    pub fn save<const N: usize>(&mut self, motors: &[MotorCalibration; N]) -> Result<(), CalibrationError> {
+        let len = record_len(N)?;
+        let mut buf = [0xFFu8; RECORD_LEN];
+
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&(N as u16).to_le_bytes());
+        for (i, motor) in motors.iter().enumerate() {
+            let start = HEADER_LEN + i * ENTRY_LEN;
+            motor.encode(&mut buf[start..start + ENTRY_LEN]);
+        }
+        let crc = crc32(&buf[..len - CRC_LEN]);
+        buf[len - CRC_LEN..len].copy_from_slice(&crc.to_le_bytes());
+
+        // writes go in whole pages, the 0xFF padding leaves the rest of the page erased
+        self.flash.erase(self.offset, self.offset + F::ERASE_SIZE as u32).map_err(|_| CalibrationError::Flash)?;
+        self.flash.write(self.offset, &buf).map_err(|_| CalibrationError::Flash)
+    }
+
+    /// Leaves the sector erased, the next load falls back to defaults.
+    pub fn clear(&mut self) -> Result<(), CalibrationError> {
+        self.flash.erase(self.offset, self.offset + F::ERASE_SIZE as u32).map_err(|_| CalibrationError::Flash)
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    pub fn with_calibration(mut self, calibration: &MotorCalibration) -> Self {
+        self.params = calibration.params;
+        self.home_offset = calibration.home_offset;
+        self
+    }
+
+    /// Current settings, ready for `CalibrationStore::save`.
+    pub fn calibration(&self) -> MotorCalibration {
+        MotorCalibration {
+            params: self.params,
+            home_offset: self.home_offset,
+        }
+    }
+
+    pub fn set_home_offset(&mut self, offset: i32) {
+        self.home_offset = offset;
+    }
+}
+
+fn record_len(motors: usize) -> Result<usize, CalibrationError> {
+    let len = HEADER_LEN + motors * ENTRY_LEN + CRC_LEN;
+    if len > RECORD_LEN {
+        return Err(CalibrationError::TooManyMotors);
+    }
+    Ok(len)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// CRC-32 (IEEE, reflected), the one zlib and most tools compute.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const SECTOR: usize = 4096;
+
+    /// One sector of NOR flash in RAM, writes can only clear bits like the real thing.
+    struct MemFlash([u8; SECTOR]);
+
+    impl MemFlash {
+        fn erased() -> Self {
+            Self([0xFF; SECTOR])
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let start = offset as usize;
+            let stored = self.0.get(start..start + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(stored);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            SECTOR
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = SECTOR;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let sector = self.0.get_mut(from as usize..to as usize).ok_or(NorFlashErrorKind::OutOfBounds)?;
+            sector.fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let start = offset as usize;
+            let stored = self.0.get_mut(start..start + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+            for (cell, byte) in stored.iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn calibrated() -> [MotorCalibration; 2] {
+        let params = MotorParams::new(400, 0.9)
+            .with_max_speed(250)
+            .with_microsteps(Driver::Tmc2209, Microsteps::Sixteenth)
+            .unwrap();
+        [MotorCalibration { params, home_offset: -1234 }, MotorCalibration::default()]
+    }
+
+    /// Flash holding `calibrated()`.
+    fn saved() -> MemFlash {
+        let mut store = CalibrationStore::new(MemFlash::erased(), 0);
+        store.save(&calibrated()).unwrap();
+        store.release()
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn saved_calibration_loads_back() {
+        let mut store = CalibrationStore::new(saved(), 0);
+        assert_eq!(store.load::<2>(), Ok(calibrated()));
+
+        // saving again rewrites the sector rather than and-ing into the old record
+        let mut changed = calibrated();
+        changed[1].home_offset = 55;
+        store.save(&changed).unwrap();
+        assert_eq!(store.load::<2>(), Ok(changed));
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_crc() {
+        let mut flash = saved();
+        flash.0[HEADER_LEN + 17] ^= 0x01;
+        let mut store = CalibrationStore::new(flash, 0);
+
+        assert_eq!(store.load::<2>(), Err(CalibrationError::Crc));
+        assert_eq!(store.load_or_default::<2>(), [MotorCalibration::default(); 2]);
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        let mut flash = saved();
+        flash.0[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let mut store = CalibrationStore::new(flash, 0);
+
+        assert_eq!(store.load::<2>(), Err(CalibrationError::Version(VERSION + 1)));
+        assert_eq!(store.load_or_default::<2>(), [MotorCalibration::default(); 2]);
+    }
+
+    #[test]
+    fn erased_flash_has_no_record() {
+        let mut store = CalibrationStore::new(MemFlash::erased(), 0);
+        assert_eq!(store.load::<2>(), Err(CalibrationError::NotFound));
+        assert_eq!(store.load_or_default::<2>(), [MotorCalibration::default(); 2]);
+
+        let mut store = CalibrationStore::new(saved(), 0);
+        store.clear().unwrap();
+        assert_eq!(store.load::<2>(), Err(CalibrationError::NotFound));
+        assert_eq!(store.load_or_default::<2>(), [MotorCalibration::default(); 2]);
+    }
+}
@@ -0,0 +1,321 @@
+use core::cell::Cell;
+
+use defmt::warn;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Timer;
+
+use super::planner::Profile;
+use super::{Motor, StateError, TurnSteps};
+
+const COMMAND_QUEUE: usize = 4;
+
+/// Moves the motion task runs one after another.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Command {
+    Turn(TurnSteps, Profile),
+    MoveTo(i32, Profile),
+    MoveBy(i32, Profile),
+    Home,
+}
+
+impl Command {
+    /// Jerk-limited moves are planned once and can't change speed halfway.
+    fn speed_fixed(&self) -> bool {
+        match self {
+            Self::Turn(_, profile) | Self::MoveTo(_, profile) | Self::MoveBy(_, profile) => matches!(profile, Profile::SCurve(_)),
+            Self::Home => false,
+        }
+    }
+}
+
+/// Changes to the move that is currently running, see `Motor::take_interrupt`.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Interrupt {
+    /// Decelerate to a standstill, moves without a ramp stop at once.
+    Stop,
+    QuickStop,
+    /// New cruise velocity in steps per second.
+    SetSpeed(f32),
+}
+
+/// Mailbox between the motion task and everyone who wants to drive its motor, meant to live in a `static`.
+///
+/// Stops and speed changes are for the move in progress, or for the next one to start when they come
+/// after it was sent. Anything sent before a command was queued is meant for a move that's already over.
+pub struct MotionControl {
+    /// Each with the number it was sent as.
+    commands: Channel<CriticalSectionRawMutex, (u32, Command), COMMAND_QUEUE>,
+    /// Number of the last command sent, stops and speeds keep it to tell which moves they came after.
+    sent: Mutex<CriticalSectionRawMutex, Cell<u32>>,
+    /// Kept apart from the speed so a later `set_speed` can't take a stop back.
+    stop: Mutex<CriticalSectionRawMutex, Cell<Option<(Interrupt, u32)>>>,
+    speed: Mutex<CriticalSectionRawMutex, Cell<Option<(f32, u32)>>>,
+    speed_fixed: Mutex<CriticalSectionRawMutex, Cell<bool>>,
+    /// Fastest `set_speed` takes, in steps per second, `MotorParams::max_steps_per_second` of the motor being driven.
+    speed_limit: Mutex<CriticalSectionRawMutex, Cell<f32>>,
+}
+
+impl MotionControl {
+    pub const fn new() -> Self {
+        Self {
+            commands: Channel::new(),
+            sent: Mutex::new(Cell::new(0)),
+            stop: Mutex::new(Cell::new(None)),
+            speed: Mutex::new(Cell::new(None)),
+            speed_fixed: Mutex::new(Cell::new(false)),
+            speed_limit: Mutex::new(Cell::new(f32::INFINITY)),
+        }
+    }
+
+    /// Queues a move, waits while the queue is full.
+    pub async fn send(&self, command: Command) {
+        let number = self.sent.lock(|sent| {
+            sent.set(sent.get().wrapping_add(1));
+            sent.get()
+        });
+        self.commands.send((number, command)).await
+    }
+
+    /// Decelerates the move in progress to a standstill and drops the queued ones, moves sent afterwards run as usual.
+    /// Doesn't turn a pending `quick_stop` into a ramped one.
+    pub fn stop(&self) {
+        self.halt(Interrupt::Stop)
+    }
+
+    /// Same as `stop`, without the ramp.
+    pub fn quick_stop(&self) {
+        self.halt(Interrupt::QuickStop)
+    }
+
+    /// Refused with `StateError::SpeedFixed` while an S-curve move runs, its plan can't be retimed, with
+    /// `StateError::TooFast` past what the motor can do, and with `StateError::InvalidProfile` unless it's positive.
+    pub fn set_speed(&self, velocity: f32) -> Result<(), StateError> {
+        if !velocity.is_finite() || velocity <= 0.0 {
+            return Err(StateError::InvalidProfile);
+        }
+        let limit = self.speed_limit.lock(|limit| limit.get());
+        if velocity > limit {
+            return Err(StateError::TooFast { requested: velocity, limit });
+        }
+        if self.speed_fixed.lock(|fixed| fixed.get()) {
+            return Err(StateError::SpeedFixed);
+        }
+        let sent = self.sent.lock(|sent| sent.get());
+        self.speed.lock(|speed| speed.set(Some((velocity, sent))));
+        Ok(())
+    }
+
+    /// Waits for the next command and gets ready to run it on a motor that goes no faster than `speed_limit` steps per second.
+    pub(crate) async fn receive(&self, speed_limit: f32) -> Command {
+        let (number, command) = self.commands.receive().await;
+        self.begin(number, &command, speed_limit);
+        command
+    }
+
+    /// Whether a stop waits to be taken, without taking it.
+    pub(crate) fn stop_pending(&self) -> bool {
+        self.stop.lock(|stop| stop.get().is_some())
+    }
+
+    /// Next change for the move in progress, a stop goes first and drops any speed change with it.
+    pub(crate) fn take_interrupt(&self) -> Option<Interrupt> {
+        if let Some((stop, _)) = self.stop.lock(|stop| stop.take()) {
+            self.speed.lock(|speed| speed.set(None));
+            return Some(stop);
+        }
+        self.speed.lock(|speed| speed.take()).map(|(velocity, _)| Interrupt::SetSpeed(velocity))
+    }
+
+    fn halt(&self, interrupt: Interrupt) {
+        let sent = self.sent.lock(|sent| sent.get());
+        self.stop.lock(|stop| {
+            let interrupt = match stop.get() {
+                Some((Interrupt::QuickStop, _)) => Interrupt::QuickStop,
+                _ => interrupt,
+            };
+            stop.set(Some((interrupt, sent)));
+        });
+        while self.commands.try_receive().is_ok() {}
+    }
+
+    /// Forgets the stop and speed sent before command `number` was, before it starts.
+    fn begin(&self, number: u32, command: &Command, speed_limit: f32) {
+        // wrapping, so it keeps working once the numbers go round
+        let before = |at: u32| (number.wrapping_sub(at) as i32) > 0;
+        self.stop.lock(|stop| {
+            if matches!(stop.get(), Some((_, at)) if before(at)) {
+                stop.set(None);
+            }
+        });
+        self.speed.lock(|speed| {
+            if matches!(speed.get(), Some((_, at)) if before(at)) {
+                speed.set(None);
+            }
+        });
+        self.speed_fixed.lock(|fixed| fixed.set(command.speed_fixed()));
+        self.speed_limit.lock(|limit| limit.set(speed_limit));
+    }
+}
+
+impl Default for MotionControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Owns the motor and runs the commands of `control`, interrupts reach the move in progress between steps.
+/// Spawn it once per motor, each with its own `MotionControl`.
+#[embassy_executor::task(pool_size = 4)]
+pub async fn motion_task(mut motor: Motor, control: &'static MotionControl) -> () {
+    motor.control = Some(control);
+
+    loop {
+        // a held driver goes to sleep when its hold time runs out before the next command
+        let speed_limit = motor.params.max_steps_per_second();
+        let command = match motor.sleep_deadline() {
+            Some(deadline) => match select(control.receive(speed_limit), Timer::at(deadline)).await {
+                Either::First(command) => command,
+                Either::Second(_) => {
+                    motor.poll_idle();
+                    continue;
+                }
+            },
+            None => control.receive(speed_limit).await,
+        };
+
+        let result = match command {
+            Command::Turn(turn_steps, profile) => motor.start_profiled(turn_steps, profile).await.map(|_| motor.position()),
+            Command::MoveTo(target, profile) => motor.move_to(target, profile).await,
+            Command::MoveBy(delta, profile) => motor.move_by(delta, profile).await,
+            Command::Home => motor.home().await,
+        };
+
+        if let Err(e) = result {
+            warn!("Motion command {:?} failed: {:?}", defmt::Debug2Format(&command), defmt::Debug2Format(&e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::stepper::{SCurveProfile, Ticks};
+
+    /// Sends `command` and takes it off the queue the way `motion_task` does.
+    fn start(control: &MotionControl, command: Command, speed_limit: f32) {
+        block_on(control.send(command));
+        block_on(control.receive(speed_limit));
+    }
+
+    #[test]
+    fn set_speed_doesnt_clear_a_stop() {
+        let control = MotionControl::new();
+        control.stop();
+        control.set_speed(100.0).unwrap();
+
+        assert_eq!(control.take_interrupt(), Some(Interrupt::Stop));
+        assert_eq!(control.take_interrupt(), None);
+    }
+
+    #[test]
+    fn stop_doesnt_soften_a_quick_stop() {
+        let control = MotionControl::new();
+        control.quick_stop();
+        control.stop();
+
+        assert_eq!(control.take_interrupt(), Some(Interrupt::QuickStop));
+    }
+
+    #[test]
+    fn newest_speed_wins() {
+        let control = MotionControl::new();
+        control.set_speed(100.0).unwrap();
+        control.set_speed(200.0).unwrap();
+
+        assert_eq!(control.take_interrupt(), Some(Interrupt::SetSpeed(200.0)));
+        assert_eq!(control.take_interrupt(), None);
+    }
+
+    #[test]
+    fn s_curve_moves_keep_their_speed() {
+        let control = MotionControl::new();
+        start(&control, Command::MoveBy(100, SCurveProfile::new(500.0, 1000.0, 5000.0).into()), f32::INFINITY);
+        assert_eq!(control.set_speed(100.0), Err(StateError::SpeedFixed));
+        assert_eq!(control.take_interrupt(), None);
+
+        start(&control, Command::MoveBy(100, Ticks(3).into()), f32::INFINITY);
+        assert_eq!(control.set_speed(100.0), Ok(()));
+    }
+
+    #[test]
+    fn a_new_move_forgets_what_was_sent_before_it() {
+        let control = MotionControl::new();
+        control.quick_stop();
+        control.set_speed(100.0).unwrap();
+
+        start(&control, Command::Home, f32::INFINITY);
+        assert_eq!(control.take_interrupt(), None);
+    }
+
+    #[test]
+    fn what_comes_after_sending_is_for_that_move() {
+        let control = MotionControl::new();
+        block_on(control.send(Command::Home));
+        control.set_speed(100.0).unwrap();
+
+        block_on(control.receive(f32::INFINITY));
+        assert_eq!(control.take_interrupt(), Some(Interrupt::SetSpeed(100.0)));
+    }
+
+    #[test]
+    fn stop_between_taking_and_starting_a_move_still_stops_it() {
+        let control = MotionControl::new();
+        block_on(control.send(Command::Home));
+
+        // the motion task took the command but hasn't started it yet
+        let (number, command) = control.commands.try_receive().unwrap();
+        control.stop();
+        control.begin(number, &command, f32::INFINITY);
+
+        assert_eq!(control.take_interrupt(), Some(Interrupt::Stop));
+    }
+
+    #[test]
+    fn stop_drops_queued_moves() {
+        let control = MotionControl::new();
+        block_on(control.send(Command::Home));
+        block_on(control.send(Command::MoveBy(10, Ticks(3).into())));
+
+        control.stop();
+        assert!(control.commands.try_receive().is_err());
+
+        // and leaves the ones sent after it alone
+        start(&control, Command::Home, f32::INFINITY);
+        assert_eq!(control.take_interrupt(), None);
+    }
+
+    #[test]
+    fn speeds_past_the_motor_are_refused() {
+        let control = MotionControl::new();
+        start(&control, Command::MoveBy(100, Ticks(3).into()), 500.0);
+
+        assert_eq!(
+            control.set_speed(600.0),
+            Err(StateError::TooFast {
+                requested: 600.0,
+                limit: 500.0
+            })
+        );
+        assert_eq!(control.set_speed(0.0), Err(StateError::InvalidProfile));
+        assert_eq!(control.set_speed(-10.0), Err(StateError::InvalidProfile));
+        assert_eq!(control.take_interrupt(), None);
+
+        assert_eq!(control.set_speed(500.0), Ok(()));
+        assert_eq!(control.take_interrupt(), Some(Interrupt::SetSpeed(500.0)));
+    }
+}
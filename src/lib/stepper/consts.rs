@@ -1,6 +1,10 @@
-pub const STEP_PIN: u8 = 4; // GP04 6th PIN
-pub const DIR_PIN: u8 = 3; // GP03 5th PIN
-pub const SLEEP_PIN: u8 = 5; // GP05 7th PIN
 pub const SPR: usize = 200; // SPR - steps per revolution
 pub const ANGLE: f32 = 1.8; // angle of single step: ANGLE * SPR = 360deg
-pub const MAX_SPEED: u64 = 450; // max microseconds stepper can handle
\ No newline at end of file
+pub const MAX_SPEED: u64 = 450; // max microseconds stepper can handle
+pub const HOMING_TICKS: u32 = 4; // ticks per step while seeking the home switch
+pub const HOMING_SLOW_TICKS: u32 = 12; // ticks per step while re-approaching the home switch
+pub const HOMING_BACKOFF: u32 = 40; // steps to back off the home switch before re-approaching
+pub const HOMING_MAX_TRAVEL: u32 = 200 * 50; // steps to give up seeking after
+pub const STALL_THRESHOLD: u8 = 60; // SGTHRS for sensorless homing, tune per motor and speed
+pub const STALL_BLANKING: u32 = 20; // steps DIAG is ignored for at the start of sensorless homing
+pub const BACKLASH_TICKS: u32 = 4; // ticks per step while taking up backlash
@@ -0,0 +1,150 @@
+use core::cell::Cell;
+
+#[cfg(feature = "rp2040")]
+use embassy_futures::select::select;
+#[cfg(feature = "rp2040")]
+use embassy_rp::gpio::{AnyPin, Input};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use super::pin::{DigitalIn, DigitalOut};
+use super::ramp::round;
+use super::{Motor, StateError, TurnSteps};
+
+// count change for every (previous AB << 2 | current AB), zero for no change or a skipped state
+#[cfg(feature = "rp2040")]
+const QUADRATURE: [i32; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Quadrature count shared between `encoder_task` and the motor, meant to live in a `static`.
+pub struct EncoderCount {
+    count: Mutex<CriticalSectionRawMutex, Cell<i32>>,
+}
+
+impl EncoderCount {
+    pub const fn new() -> Self {
+        Self {
+            count: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    pub fn get(&self) -> i32 {
+        self.count.lock(|c| c.get())
+    }
+
+    pub fn set(&self, count: i32) {
+        self.count.lock(|c| c.set(count))
+    }
+
+    fn add(&self, delta: i32) {
+        self.count.lock(|c| c.set(c.get() + delta))
+    }
+}
+
+impl Default for EncoderCount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum EncoderMode {
+    /// Abort the move with `StateError::Stalled` once the motor falls behind.
+    Monitor,
+    /// Take the measured position as the real one and add the lost steps to the move.
+    ClosedLoop,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct EncoderParams {
+    /// Encoder counts (4 per quadrature cycle) per motor step, microsteps included.
+    pub counts_per_step: f32,
+    /// Steps commanded and measured position may differ by before it counts as a stall.
+    pub tolerance: u32,
+    pub mode: EncoderMode,
+}
+
+impl EncoderParams {
+    pub fn new(counts_per_step: f32, tolerance: u32, mode: EncoderMode) -> Self {
+        Self {
+            counts_per_step,
+            tolerance,
+            mode,
+        }
+    }
+}
+
+/// Decodes the A/B channels on every edge interrupt, counts up when A leads B.
+#[cfg(feature = "rp2040")]
+#[embassy_executor::task(pool_size = 4)]
+pub async fn encoder_task(mut a: Input<'static, AnyPin>, mut b: Input<'static, AnyPin>, count: &'static EncoderCount) -> () {
+    let mut state = (a.is_high() as usize) << 1 | b.is_high() as usize;
+
+    loop {
+        select(a.wait_for_any_edge(), b.wait_for_any_edge()).await;
+
+        let next = (a.is_high() as usize) << 1 | b.is_high() as usize;
+        count.add(QUADRATURE[state << 2 | next]);
+        state = next;
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    pub fn with_encoder(mut self, count: &'static EncoderCount, params: EncoderParams) -> Self {
+        count.set(self.position_to_counts(self.position, &params));
+        self.encoder = Some((count, params));
+        self
+    }
+
+    /// Position measured by the encoder, `None` without one.
+    pub fn measured_position(&self) -> Option<i32> {
+        self.encoder.map(|(count, params)| round(count.get() as f32 / params.counts_per_step))
+    }
+
+    /// Steps the motor is behind the commanded position, negative when it's ahead.
+    pub fn lost_steps(&self) -> i32 {
+        match self.measured_position() {
+            Some(measured) => (self.position - measured) * self.direction.sign(),
+            None => 0,
+        }
+    }
+
+    /// Keeps the encoder in line when the position is set from outside (homing, `set_position`).
+    pub(crate) fn sync_encoder(&self) {
+        if let Some((count, params)) = self.encoder {
+            count.set(self.position_to_counts(self.position, &params));
+        }
+    }
+
+    /// Takes back `steps` the shaft turned without the position counting them (backlash take-up).
+    pub(crate) fn skip_encoder(&self, steps: i32) {
+        if let Some((count, params)) = self.encoder {
+            count.add(-self.position_to_counts(steps, &params));
+        }
+    }
+
+    /// Called before every step, returns the steps still to take after a closed loop correction.
+    pub(crate) fn check_encoder(&mut self, turn_steps: TurnSteps) -> Result<TurnSteps, StateError> {
+        let (measured, params) = match (self.measured_position(), self.encoder) {
+            (Some(measured), Some((_, params))) => (measured, params),
+            _ => return Ok(turn_steps),
+        };
+
+        let lost = self.lost_steps();
+        if lost.unsigned_abs() <= params.tolerance {
+            return Ok(turn_steps);
+        }
+
+        match params.mode {
+            EncoderMode::Monitor => Err(StateError::Stalled(lost)),
+            EncoderMode::ClosedLoop => {
+                self.position = measured;
+                let left = turn_steps.count() as i64 + lost as i64;
+                Ok(TurnSteps(left.max(0) as u32))
+            }
+        }
+    }
+
+    fn position_to_counts(&self, position: i32, params: &EncoderParams) -> i32 {
+        (position as f32 * params.counts_per_step) as i32
+    }
+}
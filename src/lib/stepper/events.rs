@@ -0,0 +1,129 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::{Duration, Instant};
+
+use super::pin::{DigitalIn, DigitalOut};
+use super::{Motor, StateError, Ticks, TurnSteps};
+
+const EVENT_QUEUE: usize = 8;
+const SUBSCRIBERS: usize = 4;
+
+/// Where a motor publishes its `MotionEvent`s, meant to live in a `static`. Slow subscribers miss
+/// old progress (they see a lag) but the motor never waits for them.
+pub type MotionEvents = PubSubChannel<CriticalSectionRawMutex, MotionEvent, EVENT_QUEUE, SUBSCRIBERS, 1>;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Progress {
+    pub remaining: u32,
+    pub position: i32,
+    /// Steps per second the motor is stepping at right now.
+    pub velocity: f32,
+    pub elapsed: Duration,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Outcome {
+    /// Every requested step was taken.
+    Completed,
+    /// Ended by `MotionControl::stop` or `quick_stop`.
+    Stopped,
+    /// Ended by what it was waiting for, e.g. the home switch.
+    Reached,
+    Failed(StateError),
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum MotionEvent {
+    Progress(Progress),
+    /// Once per run of the step loop, homing runs it several times.
+    Done {
+        outcome: Outcome,
+        requested: u32,
+        /// Steps actually taken, exact, counted at the step pin.
+        steps: u32,
+        position: i32,
+        elapsed: Duration,
+    },
+}
+
+/// Bookkeeping of the move in progress, for the events only.
+pub(crate) struct MoveReport {
+    started: Instant,
+    next_progress: Instant,
+    requested: u32,
+    pub(crate) stopped: bool,
+}
+
+impl MoveReport {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            next_progress: Instant::now(),
+            requested: 0,
+            stopped: false,
+        }
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Publishes a `Progress` at most every `progress_every`, and a `Done` when a move ends.
+    pub fn with_events(mut self, events: &'static MotionEvents, progress_every: Duration) -> Self {
+        self.events = Some(events);
+        self.progress_every = progress_every;
+        self
+    }
+
+    pub(crate) fn begin_report(&mut self, turn_steps: TurnSteps) {
+        let now = Instant::now();
+        self.report = MoveReport {
+            started: now,
+            next_progress: now + self.progress_every,
+            requested: turn_steps.count(),
+            stopped: false,
+        };
+    }
+
+    /// Called right after a step, `remaining` already has it taken off.
+    pub(crate) fn publish_progress(&mut self, remaining: TurnSteps, ticks_per_step: Ticks) {
+        let events = match self.events {
+            Some(events) => events,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now < self.report.next_progress {
+            return;
+        }
+        self.report.next_progress = now + self.progress_every;
+
+        let period = self.tick.as_micros() * ticks_per_step.count().max(1) as u64;
+        events.publish_immediate(MotionEvent::Progress(Progress {
+            remaining: remaining.count(),
+            position: self.position,
+            velocity: 1_000_000.0 / period.max(1) as f32,
+            elapsed: now.saturating_duration_since(self.report.started),
+        }));
+    }
+
+    pub(crate) fn publish_done(&self, result: &Result<TurnSteps, StateError>) {
+        let events = match self.events {
+            Some(events) => events,
+            None => return,
+        };
+
+        let outcome = match result {
+            Err(e) => Outcome::Failed(*e),
+            Ok(_) if self.report.stopped => Outcome::Stopped,
+            Ok(left) if left.count() > 0 => Outcome::Reached,
+            Ok(_) => Outcome::Completed,
+        };
+
+        events.publish_immediate(MotionEvent::Done {
+            outcome,
+            requested: self.report.requested,
+            steps: self.stats.steps,
+            position: self.position,
+            elapsed: Instant::now().saturating_duration_since(self.report.started),
+        });
+    }
+}
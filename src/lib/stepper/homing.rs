@@ -0,0 +1,95 @@
+use super::consts;
+use super::pin::{DigitalIn, DigitalOut};
+use super::{Direction, Motor, StateError, Ticks, TurnSteps, Until};
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct HomingParams {
+    pub direction: Direction,
+    pub active_high: bool,
+    pub approach: Ticks,
+    pub reapproach: Ticks,
+    pub backoff: u32,
+    pub max_travel: u32,
+}
+
+impl HomingParams {
+    pub fn new(direction: Direction, active_high: bool, approach: Ticks, reapproach: Ticks, backoff: u32, max_travel: u32) -> Self {
+        Self {
+            direction,
+            active_high,
+            approach,
+            reapproach,
+            backoff,
+            max_travel,
+        }
+    }
+}
+
+impl Default for HomingParams {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Backward,
+            active_high: false,
+            approach: Ticks(consts::HOMING_TICKS),
+            reapproach: Ticks(consts::HOMING_SLOW_TICKS),
+            backoff: consts::HOMING_BACKOFF,
+            max_travel: consts::HOMING_MAX_TRAVEL,
+        }
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    pub fn with_home_switch(mut self, switch: I, homing: HomingParams) -> Self {
+        self.home_switch_in = Some(switch);
+        self.homing = homing;
+        self
+    }
+
+    pub fn home_switch_active(&self) -> bool {
+        match &self.home_switch_in {
+            Some(switch) => switch.is_high() == self.homing.active_high,
+            None => false,
+        }
+    }
+
+    /// Seeks the home switch, backs off, re-approaches it slowly and sets the position to the home offset there.
+    /// Fails with `StateError::HomeSwitchStuck` if the switch is still active once backed off.
+    /// Motors with only a stall DIAG input home sensorless instead.
+    pub async fn home(&mut self) -> Result<i32, StateError> {
+        if self.home_switch_in.is_none() {
+            if self.diag_in.is_some() {
+                return self.home_sensorless().await.map(|result| result.position);
+            }
+            return Err(StateError::NoHomeSwitch);
+        }
+
+        let homing = self.homing;
+
+        self.set_dir(homing.direction).await?;
+        self.seek(TurnSteps(homing.max_travel), homing.approach).await?;
+
+        // travel limits are meaningless until homed, so back off through the unchecked path
+        self.set_dir(homing.direction.reversed()).await?;
+        self.turn_constant(TurnSteps(homing.backoff), homing.approach, Until::Done).await?;
+        // re-approaching from here would find it without moving and home wherever the motor stands
+        if self.home_switch_active() {
+            return Err(StateError::HomeSwitchStuck);
+        }
+
+        self.set_dir(homing.direction).await?;
+        self.seek(TurnSteps(homing.backoff.saturating_mul(2)), homing.reapproach).await?;
+
+        self.position = self.home_offset;
+        self.sync_encoder();
+        Ok(self.position)
+    }
+
+    async fn seek(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks) -> Result<(), StateError> {
+        self.turn_constant(turn_steps, ticks_per_step, Until::HomeSwitch).await?;
+
+        if !self.home_switch_active() {
+            return Err(StateError::HomeNotFound);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use embassy_time::{Duration, Instant, Timer};
+
+use super::pin::{DigitalIn, DigitalOut};
+use super::Motor;
+
+/// What happens to the driver (its sleep pin) once a move is over.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum IdlePolicy {
+    /// Drop the holding torque as soon as the motor stops.
+    #[default]
+    SleepImmediately,
+    /// Keep holding for a while, a move starting within that time doesn't have to wake the driver.
+    /// Nothing sleeps the driver on its own when the time runs out: `motion_task` and `sequence_task` do it while they
+    /// wait, anyone else driving the motor (or an `Axes` / `gcode::Machine`) has to await `idle` or call `poll_idle`.
+    Hold(Duration),
+    /// Never sleep on its own, only `set_enabled(false)` does.
+    AlwaysOn,
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// `wake_settle` is how long the driver needs after leaving sleep before it takes the first step.
+    pub fn with_idle_policy(mut self, policy: IdlePolicy, wake_settle: Duration) -> Self {
+        self.idle_policy = policy;
+        self.wake_settle = wake_settle;
+        if policy == IdlePolicy::AlwaysOn {
+            self.sleep_pin_ot.set_high();
+        }
+        self
+    }
+
+    /// Takes effect at the end of the next move.
+    pub fn set_idle_policy(&mut self, policy: IdlePolicy) {
+        self.idle_policy = policy;
+    }
+
+    /// When a held driver goes to sleep, `None` if it's already asleep or held for good.
+    pub fn sleep_deadline(&self) -> Option<Instant> {
+        self.sleep_at
+    }
+
+    /// Puts the driver to sleep once the hold time ran out, returns whether it's asleep.
+    pub fn poll_idle(&mut self) -> bool {
+        if let Some(at) = self.sleep_at {
+            if Instant::now() >= at {
+                self.sleep_at = None;
+                self.sleep_pin_ot.set_low();
+            }
+        }
+        self.sleep_pin_ot.is_set_low()
+    }
+
+    /// Waits out the hold time, for tasks that finish after their move.
+    pub async fn idle(&mut self) {
+        if let Some(at) = self.sleep_at {
+            Timer::at(at).await;
+            self.poll_idle();
+        }
+    }
+
+    /// Enables the driver, returns how long it has to settle before stepping.
+    pub(crate) fn wake_up(&mut self) -> Duration {
+        self.sleep_at = None;
+        if self.sleep_pin_ot.is_set_low() {
+            self.sleep_pin_ot.set_high();
+            return self.wake_settle;
+        }
+        Duration::from_micros(0)
+    }
+
+    pub(crate) async fn wake(&mut self) {
+        let settle = self.wake_up();
+        if settle.as_micros() > 0 {
+            Timer::after(settle).await;
+        }
+    }
+
+    /// Called when a move ends, successfully or not.
+    pub(crate) fn release(&mut self) {
+        match self.idle_policy {
+            IdlePolicy::SleepImmediately => self.sleep_pin_ot.set_low(),
+            IdlePolicy::Hold(hold) => self.sleep_at = Some(Instant::now() + hold),
+            IdlePolicy::AlwaysOn => {}
+        }
+    }
+}
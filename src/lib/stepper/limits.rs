@@ -0,0 +1,71 @@
+use super::pin::{DigitalIn, DigitalOut};
+use super::{Direction, Motor, StateError, TurnSteps};
+
+/// Soft travel range in absolute steps, moves past it are refused or, with `clamp`, shortened to end at the limit.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TravelLimits {
+    pub min: i32,
+    pub max: i32,
+    pub clamp: bool,
+}
+
+impl TravelLimits {
+    pub fn new(min: i32, max: i32, clamp: bool) -> Self {
+        Self { min, max, clamp }
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    pub fn with_travel_limits(mut self, limits: TravelLimits) -> Self {
+        self.travel_limits = Some(limits);
+        self
+    }
+
+    /// End-stops abort any move heading into them with `StateError::EndStopTriggered`.
+    pub fn with_end_stops(mut self, min_stop: Option<I>, max_stop: Option<I>, active_high: bool) -> Self {
+        self.min_stop_in = min_stop;
+        self.max_stop_in = max_stop;
+        self.end_stops_active_high = active_high;
+        self
+    }
+
+    pub fn set_travel_limits(&mut self, limits: Option<TravelLimits>) {
+        self.travel_limits = limits;
+    }
+
+    /// Only the stop in the direction of travel counts, so the motor can always back off a tripped one.
+    pub(crate) fn end_stop_hit(&self) -> bool {
+        let stop = match self.direction {
+            Direction::Forward => &self.max_stop_in,
+            Direction::Backward => &self.min_stop_in,
+        };
+
+        match stop {
+            Some(stop) => stop.is_high() == self.end_stops_active_high,
+            None => false,
+        }
+    }
+
+    pub(crate) fn limit_steps(&self, turn_steps: TurnSteps) -> Result<TurnSteps, StateError> {
+        let limits = match self.travel_limits {
+            Some(limits) => limits,
+            None => return Ok(turn_steps),
+        };
+
+        let position = self.position as i64;
+        let target = position + self.direction.sign() as i64 * turn_steps.count() as i64;
+        if target >= limits.min as i64 && target <= limits.max as i64 {
+            return Ok(turn_steps);
+        }
+
+        if !limits.clamp {
+            return Err(StateError::OutOfTravel);
+        }
+
+        let allowed = match self.direction {
+            Direction::Forward => limits.max as i64 - position,
+            Direction::Backward => position - limits.min as i64,
+        };
+        Ok(TurnSteps(allowed.max(0) as u32))
+    }
+}
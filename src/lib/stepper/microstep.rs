@@ -0,0 +1,185 @@
+#[cfg(feature = "rp2040")]
+use embassy_rp::gpio::{AnyPin, Level, Output, Pin};
+
+use super::pin::{DigitalIn, DigitalOut};
+use super::ramp::round;
+use super::{Motor, MotorParams, State, StateError};
+
+/// Driver chip, the MS1/MS2/MS3 (MODE0/1/2) truth tables differ between them.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Driver {
+    A4988,
+    Drv8825,
+    /// Resolution is set over UART (`Tmc2209`), MS1/MS2 pick its address.
+    Tmc2209,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Microsteps {
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+}
+
+impl Microsteps {
+    /// Microsteps per full step.
+    pub fn factor(&self) -> u32 {
+        match self {
+            Self::Full => 1,
+            Self::Half => 2,
+            Self::Quarter => 4,
+            Self::Eighth => 8,
+            Self::Sixteenth => 16,
+            Self::ThirtySecond => 32,
+        }
+    }
+
+    /// Levels of MS1, MS2 and MS3, `None` when the driver can't do this resolution (or has no MS pins).
+    pub fn levels(&self, driver: Driver) -> Option<[bool; 3]> {
+        match (driver, self) {
+            (Driver::Tmc2209, _) => None,
+            (_, Self::Full) => Some([false, false, false]),
+            (_, Self::Half) => Some([true, false, false]),
+            (_, Self::Quarter) => Some([false, true, false]),
+            (_, Self::Eighth) => Some([true, true, false]),
+            (Driver::A4988, Self::Sixteenth) => Some([true, true, true]),
+            (Driver::A4988, Self::ThirtySecond) => None,
+            (Driver::Drv8825, Self::Sixteenth) => Some([false, false, true]),
+            (Driver::Drv8825, Self::ThirtySecond) => Some([true, false, true]),
+        }
+    }
+
+    /// Whether `driver` can step at this resolution at all, by MS pins or (TMC2209) by register.
+    pub fn supported_by(&self, driver: Driver) -> bool {
+        driver == Driver::Tmc2209 || self.levels(driver).is_some()
+    }
+}
+
+impl MotorParams {
+    pub fn with_microsteps(mut self, driver: Driver, microsteps: Microsteps) -> Result<Self, StateError> {
+        if !microsteps.supported_by(driver) {
+            return Err(StateError::UnsupportedMicrosteps);
+        }
+        self.driver = driver;
+        self.microsteps = microsteps;
+        Ok(self)
+    }
+
+    pub fn steps_per_revolution(&self) -> u32 {
+        self.spr as u32 * self.microsteps.factor()
+    }
+
+    pub fn step_angle(&self) -> f32 {
+        self.angle / self.microsteps.factor() as f32
+    }
+
+    pub fn degrees_to_steps(&self, degrees: f32) -> i32 {
+        round(degrees / self.step_angle())
+    }
+
+    pub fn revolutions_to_steps(&self, revolutions: f32) -> i32 {
+        round(revolutions * self.steps_per_revolution() as f32)
+    }
+
+    pub fn steps_to_degrees(&self, steps: i32) -> f32 {
+        steps as f32 * self.step_angle()
+    }
+}
+
+#[cfg(feature = "rp2040")]
+impl<I: DigitalIn> Motor<Output<'static, AnyPin>, I> {
+    /// MS1, MS2 and MS3 (MODE0, MODE1 and MODE2 on the DRV8825).
+    pub fn with_microstep_gpio(self, ms1_pin_ow: impl Pin, ms2_pin_ow: impl Pin, ms3_pin_ow: impl Pin) -> Result<Self, StateError> {
+        self.with_microstep_pins([
+            Output::new(ms1_pin_ow.degrade(), Level::Low),
+            Output::new(ms2_pin_ow.degrade(), Level::Low),
+            Output::new(ms3_pin_ow.degrade(), Level::Low),
+        ])
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Refused for a TMC2209 (its MS pins are the UART address) and resolutions the driver can't do.
+    pub fn with_microstep_pins(mut self, ms_ot: [P; 3]) -> Result<Self, StateError> {
+        if self.params.microsteps.levels(self.params.driver).is_none() {
+            return Err(StateError::UnsupportedMicrosteps);
+        }
+        self.ms_pins_ot = Some(ms_ot);
+        self.write_microsteps(self.params.microsteps);
+        Ok(self)
+    }
+
+    /// Switches resolution while idle, everything counted in steps is rescaled so it stays the same
+    /// physical angle: the position exactly (refused with `StateError::UnalignedPosition` when it sits
+    /// between steps of a coarser resolution), travel limits rounded inward, the home offset to the
+    /// nearest step, backlash up, and the encoder's counts per step.
+    /// A TMC2209 has to be told separately, see `Tmc2209::set_microsteps`.
+    pub fn set_microsteps(&mut self, microsteps: Microsteps) -> Result<(), StateError> {
+        if self.state != State::Idle {
+            return Err(StateError::AlreadyTurning);
+        }
+
+        if !microsteps.supported_by(self.params.driver) {
+            return Err(StateError::UnsupportedMicrosteps);
+        }
+
+        let from = self.params.microsteps.factor() as i64;
+        let to = microsteps.factor() as i64;
+        if (self.position as i64 * to) % from != 0 {
+            return Err(StateError::UnalignedPosition);
+        }
+
+        self.position = (self.position as i64 * to / from) as i32;
+        self.home_offset = rescale_nearest(self.home_offset, from, to);
+        self.backlash = rescale_up(self.backlash as i32, from, to) as u32;
+        self.owed = rescale_up(self.owed as i32, from, to).min(self.backlash as i32) as u32;
+        if let Some(limits) = &mut self.travel_limits {
+            limits.min = rescale_up(limits.min, from, to);
+            limits.max = rescale_down(limits.max, from, to);
+        }
+        if let Some((_, params)) = &mut self.encoder {
+            params.counts_per_step = params.counts_per_step * from as f32 / to as f32;
+        }
+        self.sync_encoder();
+
+        self.params.microsteps = microsteps;
+        self.write_microsteps(microsteps);
+        Ok(())
+    }
+
+    pub fn position_degrees(&self) -> f32 {
+        self.params.steps_to_degrees(self.position)
+    }
+
+    fn write_microsteps(&mut self, microsteps: Microsteps) {
+        let (pins, levels) = match (&mut self.ms_pins_ot, microsteps.levels(self.params.driver)) {
+            (Some(pins), Some(levels)) => (pins, levels),
+            _ => return,
+        };
+
+        for (pin, high) in pins.iter_mut().zip(levels) {
+            if high {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+    }
+}
+
+// `steps` counted at `from` microsteps per full step, in steps at `to`
+fn rescale_down(steps: i32, from: i64, to: i64) -> i32 {
+    (steps as i64 * to).div_euclid(from) as i32
+}
+
+fn rescale_up(steps: i32, from: i64, to: i64) -> i32 {
+    let down = (-(steps as i64) * to).div_euclid(from);
+    (-down) as i32
+}
+
+fn rescale_nearest(steps: i32, from: i64, to: i64) -> i32 {
+    (2 * steps as i64 * to + from).div_euclid(2 * from) as i32
+}
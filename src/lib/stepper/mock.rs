@@ -0,0 +1,157 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_time::Instant;
+use heapless::Vec;
+
+use super::pin::{DigitalIn, DigitalOut};
+
+const MAX_EVENTS: usize = 1024;
+const MAX_EDGES: usize = 8;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Line {
+    Step,
+    Dir,
+    Sleep,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct PinEvent {
+    pub line: Line,
+    pub high: bool,
+    pub at: Instant,
+}
+
+/// Shared log of every level change of the pins handed out by `pin`, oldest first.
+/// Events past `MAX_EVENTS` are dropped.
+pub struct Recorder {
+    events: RefCell<Vec<PinEvent, MAX_EVENTS>>,
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Self {
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn pin(&self, line: Line, high: bool) -> RecordingPin<'_> {
+        RecordingPin {
+            recorder: self,
+            line,
+            high,
+        }
+    }
+
+    pub fn events(&self) -> Vec<PinEvent, MAX_EVENTS> {
+        self.events.borrow().clone()
+    }
+
+    /// Rising edges of `line`, for the step line that's the number of steps taken.
+    pub fn rising_edges(&self, line: Line) -> usize {
+        self.events
+            .borrow()
+            .iter()
+            .filter(|e| e.line == line && e.high)
+            .count()
+    }
+
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    fn record(&self, line: Line, high: bool) {
+        let _ = self.events.borrow_mut().push(PinEvent {
+            line,
+            high,
+            at: Instant::now(),
+        });
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Host stand-in for a gpio output, every level change is written to its `Recorder`.
+pub struct RecordingPin<'a> {
+    recorder: &'a Recorder,
+    line: Line,
+    high: bool,
+}
+
+impl<'a> RecordingPin<'a> {
+    pub fn is_set_high(&self) -> bool {
+        self.high
+    }
+
+    fn set(&mut self, high: bool) {
+        self.high = high;
+        self.recorder.record(self.line, high);
+    }
+}
+
+impl<'a> DigitalOut for RecordingPin<'a> {
+    fn set_high(&mut self) {
+        self.set(true)
+    }
+
+    fn set_low(&mut self) {
+        self.set(false)
+    }
+
+    fn toggle(&mut self) {
+        self.set(!self.high)
+    }
+
+    fn is_set_low(&self) -> bool {
+        !self.high
+    }
+}
+
+/// Host stand-in for a gpio input, reads low until it was read `trip_after` times, then high.
+pub struct MockInput {
+    reads: Cell<u32>,
+    /// Reads after which the level flips, starting out low.
+    edges: RefCell<Vec<u32, MAX_EDGES>>,
+}
+
+impl MockInput {
+    pub fn new(trip_after: u32) -> Self {
+        Self::toggling(&[trip_after])
+    }
+
+    /// Reads low and flips after as many reads as each of `edges` says, counted from the start, e.g. a
+    /// switch that's hit, released and hit again.
+    pub fn toggling(edges: &[u32]) -> Self {
+        Self {
+            reads: Cell::new(0),
+            edges: RefCell::new(Vec::from_slice(edges).expect("too many edges")),
+        }
+    }
+
+    pub fn set_high(&self) {
+        self.reads.set(0);
+        *self.edges.borrow_mut() = Vec::from_slice(&[0]).unwrap();
+    }
+
+    pub fn set_low(&self) {
+        self.edges.borrow_mut().clear();
+    }
+}
+
+impl DigitalIn for MockInput {
+    fn is_high(&self) -> bool {
+        let read = self.reads.get();
+        self.reads.set(read.saturating_add(1));
+        let flips = self
+            .edges
+            .borrow()
+            .iter()
+            .filter(|&&edge| edge <= read)
+            .count();
+        flips % 2 == 1
+    }
+}
@@ -1,45 +1,101 @@
 use core::fmt::Debug;
 
-use embassy_rp::gpio::{AnyPin, Output, Pin, Level};
+#[cfg(feature = "rp2040")]
+use embassy_rp::gpio::{Output, Pin, Level};
 use embassy_time::{Timer, Duration, Instant};
 
-// ---------------------------------
-// UNCOMMENT ONLY FOR DEBUG PURPOSES
-// ---------------------------------
-use crate::rlog;
-use crate::rlog::color::Color;
-use crate::rlog::log;
-
+mod axes;
+mod backlash;
+mod calibration;
+mod command;
 mod consts;
-
+mod encoder;
+mod events;
+mod homing;
+mod idle;
+mod limits;
+mod microstep;
+mod pin;
+#[cfg(feature = "rp2040")]
+mod pio_motor;
+mod planner;
+mod queue;
+mod ramp;
+mod scurve;
+mod sensorless;
+mod stats;
+mod tmc2209;
+mod units;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(test)]
+mod tests;
+
+pub(crate) use command::Interrupt;
+use events::MoveReport;
+use planner::Planner;
+
+pub use axes::Axes;
+pub use calibration::{CalibrationError, CalibrationStore, MotorCalibration, DEFAULT_OFFSET as CALIBRATION_OFFSET};
+pub use command::{motion_task, Command, MotionControl};
+#[cfg(feature = "rp2040")]
+pub use encoder::encoder_task;
+pub use encoder::{EncoderCount, EncoderMode, EncoderParams};
+pub use events::{MotionEvent, MotionEvents, Outcome, Progress};
+pub use homing::HomingParams;
+pub use idle::IdlePolicy;
+pub use limits::TravelLimits;
+pub use microstep::{Driver, Microsteps};
+pub use pin::{DigitalIn, DigitalOut};
+#[cfg(feature = "rp2040")]
+pub use pio_motor::PioMotor;
+pub use planner::Profile;
+pub use queue::MotionQueue;
+pub use ramp::{TrapezoidalProfile, TrapezoidalRamp};
+pub(crate) use ramp::{round, sqrt};
+pub use scurve::{SCurveProfile, SCurveRamp};
+pub use sensorless::{HomingResult, SensorlessHoming};
+pub use stats::TimingStats;
+pub use tmc2209::{Tmc2209, TmcConfig, TmcError};
+pub use units::{Angle, Speed, UnitTraits};
+
+/// What the motor and its driver are, the pins it's wired to are handed to `Motor::new` directly.
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub struct MotorParams {
-    pub step_pin: u8,
-    pub dir_pin: u8,
-    pub sleep_pin: u8,
     pub spr: usize,
-    pub angle: f32
+    pub angle: f32,
+    pub driver: Driver,
+    pub microsteps: Microsteps,
+    /// Shortest step interval in microseconds, moves asking for more are refused with `StateError::TooFast`.
+    pub max_speed: u64,
 }
 
 impl MotorParams {
-    pub fn new(step_pin: u8, dir_pin: u8, sleep_pin: u8, spr: usize, angle: f32) -> Self {
+    pub fn new(spr: usize, angle: f32) -> Self {
         Self {
-            step_pin,
-            dir_pin,
-            sleep_pin,
             spr,
-            angle
+            angle,
+            driver: Driver::A4988,
+            microsteps: Microsteps::Full,
+            max_speed: consts::MAX_SPEED,
         }
     }
+
+    pub fn with_max_speed(mut self, max_speed: u64) -> Self {
+        self.max_speed = max_speed.max(1);
+        self
+    }
 }
 
 impl Default for MotorParams {
     fn default() -> Self {
         Self {
-            step_pin: consts::STEP_PIN,
-            dir_pin: consts::DIR_PIN,
-            sleep_pin: consts::SLEEP_PIN,
             spr: consts::SPR,
-            angle: consts::ANGLE
+            angle: consts::ANGLE,
+            driver: Driver::A4988,
+            microsteps: Microsteps::Full,
+            max_speed: consts::MAX_SPEED,
         }
     }
 }
@@ -49,7 +105,7 @@ enum Pulse {
     StepsUntilPulse(u32),
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub struct TurnSteps(pub u32);
 
 impl TurnSteps {
@@ -58,7 +114,7 @@ impl TurnSteps {
     }
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub struct Ticks(pub u32);
 
 impl Ticks {
@@ -82,10 +138,62 @@ impl NumberTraits for u32 {
     }
 }
 
-#[derive(Debug)]
+/// Forward is the direction with dir pin low, steps taken forward increase the position.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+impl Direction {
+    fn reversed(&self) -> Self {
+        match self {
+            Self::Forward => Self::Backward,
+            Self::Backward => Self::Forward,
+        }
+    }
+
+    fn sign(&self) -> i32 {
+        match self {
+            Self::Forward => 1,
+            Self::Backward => -1,
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum StateError { 
     AlreadyTurning,
     NeedMoreTicksPerStep,
+    InvalidProfile,
+    NoHomeSwitch,
+    HomeNotFound,
+    /// The home switch was still active after backing off it, so it can't be approached again.
+    HomeSwitchStuck,
+    OutOfTravel,
+    EndStopTriggered,
+    UnsupportedMicrosteps,
+    /// The position sits between two steps of the coarser resolution `set_microsteps` was asked for.
+    UnalignedPosition,
+    /// Requested and highest allowed speed, both in steps per second.
+    TooFast { requested: f32, limit: f32 },
+    /// Register access to the driver chip failed.
+    DriverComm,
+    /// Steps the encoder says the motor fell behind (negative when ahead).
+    Stalled(i32),
+    /// `MotionControl::set_speed` during a move planned once for its whole length (S-curve).
+    SpeedFixed,
+    /// `MotionQueue::push` while every slot holds a segment that hasn't run yet.
+    QueueFull,
+}
+
+/// What besides running out of steps ends a move.
+#[derive(PartialEq, Copy, Clone)]
+enum Until {
+    Done,
+    HomeSwitch,
+    /// DIAG, once no more than this many steps are left, see `SensorlessHoming::blanking`.
+    Stall(u32),
 }
 
 #[derive(PartialEq)]
@@ -94,30 +202,50 @@ enum State {
     Moving {
         turn_steps: TurnSteps,
         ticks_per_step: Ticks,
+        until: Until,
     },
 }
 
-pub struct Motor {
+pub struct Motor<P: DigitalOut = pin::DefaultOut, I: DigitalIn = pin::DefaultIn> {
     pub params: MotorParams,
-    step_pin_ot: Output<'static, AnyPin>,
-    dir_pin_ot: Output<'static, AnyPin>,
-    sleep_pin_ot: Output<'static, AnyPin>,
-    pub steps: usize,
+    step_pin_ot: P,
+    dir_pin_ot: P,
+    sleep_pin_ot: P,
+    ms_pins_ot: Option<[P; 3]>,
+    home_switch_in: Option<I>,
+    homing: HomingParams,
+    diag_in: Option<I>,
+    sensorless: SensorlessHoming,
+    home_offset: i32,
+    backlash: u32,
+    slack: Option<Direction>,
+    owed: u32,
+    min_stop_in: Option<I>,
+    max_stop_in: Option<I>,
+    end_stops_active_high: bool,
+    travel_limits: Option<TravelLimits>,
+    control: Option<&'static MotionControl>,
+    encoder: Option<(&'static EncoderCount, EncoderParams)>,
+    idle_policy: IdlePolicy,
+    wake_settle: Duration,
+    sleep_at: Option<Instant>,
+    position: i32,
+    direction: Direction,
     pulse: Pulse,
     state: State,
     last: Option<Instant>,
     hanger: Duration,
+    tick: Duration,
+    planner: Planner,
+    stats: TimingStats,
+    events: Option<&'static MotionEvents>,
+    progress_every: Duration,
+    report: MoveReport,
 }
 
+#[cfg(feature = "rp2040")]
 impl Motor {
     pub fn new(params: MotorParams, step_pin_ow: impl Pin, dir_pin_ow: impl Pin, sleep_pin_ow: impl Pin) -> Self {
-        if step_pin_ow.pin() != params.step_pin
-           || dir_pin_ow.pin() != params.dir_pin
-           || sleep_pin_ow.pin() != params.sleep_pin
-        {
-            panic!("Passed wrong pins!");
-        }
-
         let step_pin_ow = step_pin_ow.degrade();
         let dir_pin_ow = dir_pin_ow.degrade();
         let sleep_pin_ow =sleep_pin_ow.degrade();
@@ -126,16 +254,48 @@ impl Motor {
         let dir_ot = Output::new(dir_pin_ow, Level::Low);
         let sleep_ot = Output::new(sleep_pin_ow, Level::High);
 
+        Motor::from_outputs(params, step_ot, dir_ot, sleep_ot)
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Builds the motor on already configured outputs, no pin checks against `params` are done.
+    pub fn from_outputs(params: MotorParams, step_ot: P, dir_ot: P, sleep_ot: P) -> Self {
         Motor {
             params,
             step_pin_ot: step_ot,
             dir_pin_ot: dir_ot,
             sleep_pin_ot: sleep_ot,
-            steps: 0,
+            ms_pins_ot: None,
+            home_switch_in: None,
+            homing: Default::default(),
+            diag_in: None,
+            sensorless: Default::default(),
+            home_offset: 0,
+            backlash: 0,
+            slack: None,
+            owed: 0,
+            min_stop_in: None,
+            max_stop_in: None,
+            end_stops_active_high: false,
+            travel_limits: None,
+            control: None,
+            encoder: None,
+            idle_policy: Default::default(),
+            wake_settle: Duration::from_micros(0),
+            sleep_at: None,
+            position: 0,
+            direction: Direction::Forward,
             pulse: Pulse::StepsUntilPulse(0),
             state: State::Idle,
             last: None,
             hanger: Duration::from_micros(0),
+            tick: Duration::from_micros(params.max_speed),
+            planner: Planner::Constant,
+            stats: TimingStats::new(),
+            events: None,
+            progress_every: Duration::from_millis(100),
+            report: MoveReport::new(),
         }
     }
 
@@ -144,39 +304,142 @@ impl Motor {
             return Err(StateError::AlreadyTurning)
         } 
         self.dir_pin_ot.toggle();
+        self.direction = self.direction.reversed();
         Ok(())
     }
 
+    pub async fn set_dir(&mut self, direction: Direction) -> Result<(), StateError> {
+        if self.direction != direction {
+            self.toggle_dir().await?;
+        }
+        Ok(())
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Changes the speed limit, a move in progress keeps to the new one from its next step.
+    pub fn set_max_speed(&mut self, max_speed: u64) {
+        self.params.max_speed = max_speed.max(1);
+    }
+
+    /// Absolute position in steps, counted from power-up or the last `set_position`.
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: i32) {
+        self.position = position;
+        self.sync_encoder();
+    }
+
+    /// Drives the sleep pin, moves wake the driver up by themselves and leave it as the `IdlePolicy` says.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.sleep_at = None;
+        if enabled {
+            self.sleep_pin_ot.set_high();
+        } else {
+            self.sleep_pin_ot.set_low();
+        }
+    }
+
+    /// Turns to the absolute `target` position, returns the position the motor ended at.
+    pub async fn move_to(&mut self, target: i32, profile: impl Into<Profile>) -> Result<i32, StateError> {
+        self.move_by(target - self.position, profile).await
+    }
+
+    /// Turns `delta` steps away from the current position, negative deltas turn backward.
+    pub async fn move_by(&mut self, delta: i32, profile: impl Into<Profile>) -> Result<i32, StateError> {
+        let direction = if delta < 0 { Direction::Backward } else { Direction::Forward };
+        self.set_dir(direction).await?;
+
+        self.start_profiled(TurnSteps(delta.unsigned_abs()), profile).await?;
+        Ok(self.position)
+    }
+
     pub async fn start_turning(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks) -> Result<(), StateError> {
+        let turn_steps = self.limit_steps(turn_steps)?;
+        self.turn_constant(turn_steps, ticks_per_step, Until::Done).await.map(|_| ())
+    }
+
+    /// Same as `start_turning`, but every step lasts a single tick whose length is planned from the profile.
+    pub async fn start_profiled(&mut self, turn_steps: TurnSteps, profile: impl Into<Profile>) -> Result<(), StateError> {
+        let profile = profile.into();
+        if let Profile::Constant(ticks) = profile {
+            return self.start_turning(turn_steps, ticks).await;
+        }
+
+        let turn_steps = self.limit_steps(turn_steps)?;
+        if self.state == State::Idle {
+            if !profile.is_valid() {
+                return Err(StateError::InvalidProfile);
+            }
+            self.params.check_profile(&profile)?;
+            if let Some(interval) = profile.interval() {
+                self.tick = Duration::from_micros(interval.max(self.params.max_speed));
+            }
+            self.planner = Planner::new(profile, turn_steps.count());
+        }
+
+        self.turn(turn_steps, Ticks(2), Until::Done).await.map(|_| ())
+    }
+
+    async fn turn_constant(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks, until: Until) -> Result<TurnSteps, StateError> {
+        if self.state == State::Idle {
+            self.tick = Duration::from_micros(self.params.max_speed);
+            self.planner = Planner::Constant;
+        }
+
+        self.turn(turn_steps, ticks_per_step, until).await
+    }
+
+    /// Runs the step state machine, returns the steps that were left when `until` ended the move early.
+    async fn turn(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks, until: Until) -> Result<TurnSteps, StateError> {
         if self.state != State::Idle {
-            self.sleep_pin_ot.set_low();
             return Err(StateError::AlreadyTurning)
         }
 
-        if ticks_per_step.count() < 1 {
-            self.sleep_pin_ot.set_low();
-            return Err(StateError::NeedMoreTicksPerStep);
-        }
+        self.params.check_ticks(ticks_per_step)?;
 
         self.state = State::Moving { 
             turn_steps, 
             ticks_per_step: Ticks(ticks_per_step.count() - 1),
+            until,
         };
         self.pulse = Pulse::StepsUntilPulse(0);
+        self.stats = TimingStats::new();
+        self.begin_report(turn_steps);
 
-        if self.sleep_pin_ot.is_set_low() {
-            self.sleep_pin_ot.set_high();
-        }
+        self.wake().await;
+        let taken_up = match turn_steps.count() {
+            0 => Ok(()),
+            _ => self.take_up_backlash().await,
+        };
+        let result = match taken_up {
+            Ok(()) => self.run_steps().await,
+            Err(e) => {
+                self.state = State::Idle;
+                Err(e)
+            }
+        };
+        self.release();
+        self.report_timing();
+        self.publish_done(&result);
 
+        result
+    }
+
+    async fn run_steps(&mut self) -> Result<TurnSteps, StateError> {
         loop {
             match self.state {
                 State::Idle => {
-                    self.sleep_pin_ot.set_low();
-                    return Ok(())
+                    return Ok(TurnSteps(0))
                 },
                 State::Moving { 
                     turn_steps, 
-                    ticks_per_step 
+                    ticks_per_step,
+                    until,
                 } => {
                     self.pulse = match self.pulse {
                         Pulse::High => {
@@ -184,30 +447,49 @@ impl Motor {
                             Pulse::StepsUntilPulse(ticks_per_step.count())
                         }
                         Pulse::StepsUntilPulse(0) => {
-                            if turn_steps.count() == 0 {
+                            let turn_steps = self.take_interrupt(turn_steps, ticks_per_step);
+                            let turn_steps = match self.check_encoder(turn_steps) {
+                                Ok(turn_steps) => turn_steps,
+                                Err(e) => {
+                                    self.state = State::Idle;
+                                    return Err(e)
+                                }
+                            };
+                            if turn_steps.count() == 0 || self.reached(until, turn_steps) {
                                 self.state = State::Idle;
-                                self.sleep_pin_ot.set_low();
-                                return Ok(())
+                                return Ok(turn_steps)
+                            }
+                            if self.end_stop_hit() {
+                                self.state = State::Idle;
+                                return Err(StateError::EndStopTriggered)
                             }
                             self.state = State::Moving { 
                                 turn_steps: TurnSteps(turn_steps.count() - 1),
                                 ticks_per_step,
+                                until,
                             };
                             
+                            if let Some(interval) = self.planner.next_interval() {
+                                self.tick = Duration::from_micros(interval.max(self.params.max_speed));
+                            }
+
                             self.last = Some(Instant::now());
                             self.hanger = Duration::from_micros(0);
 
                             self.step_pin_ot.set_high();
+                            self.stats.step(Instant::now());
+                            self.position += self.direction.sign();
+                            self.publish_progress(TurnSteps(turn_steps.count() - 1), ticks_per_step);
                             Pulse::High
                         }
                         Pulse::StepsUntilPulse(n) => {
                             let (sleep, hanger) = self.calc_sleep();
+                            let due = Instant::now() + sleep;
                             Timer::after(sleep).await;
 
                             self.hanger = hanger;
                             self.last = Some(Instant::now());
-
-                            rlog!(&"da");
+                            self.stats.woke(due, hanger);
 
                             Pulse::StepsUntilPulse(n - 1)
                         },
@@ -217,13 +499,64 @@ impl Motor {
         }
      }
 
+    /// Applies a pending `Interrupt` from the motion task, returns the steps left after it.
+    fn take_interrupt(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks) -> TurnSteps {
+        let interrupt = match self.control {
+            Some(control) => control.take_interrupt(),
+            None => None,
+        };
+
+        match interrupt {
+            None => turn_steps,
+            Some(Interrupt::QuickStop) => {
+                self.report.stopped = true;
+                TurnSteps(0)
+            }
+            Some(Interrupt::Stop) => {
+                self.report.stopped = true;
+                if !self.planner.stop() {
+                    return TurnSteps(0);
+                }
+                TurnSteps(turn_steps.count().min(self.planner.remaining().unwrap_or(0)))
+            }
+            Some(Interrupt::SetSpeed(velocity)) => {
+                // `MotionControl::set_speed` refuses these up front once `motion_task` told it the motor's limit,
+                // a motor driven on its own drops them here rather than running at some other speed
+                if velocity <= 0.0 || self.params.check_speed(velocity).is_err() {
+                    return turn_steps;
+                }
+                if !self.planner.set_speed(velocity) {
+                    let period = 1_000_000.0 / velocity;
+                    self.tick = Duration::from_micros((period / ticks_per_step.count().max(1) as f32) as u64);
+                }
+                turn_steps
+            }
+        }
+    }
+
+    /// Whether a stop waits for `take_interrupt`, it stays pending.
+    fn stop_requested(&self) -> bool {
+        match self.control {
+            Some(control) => control.stop_pending(),
+            None => false,
+        }
+    }
+
+    fn reached(&self, until: Until, turn_steps: TurnSteps) -> bool {
+        match until {
+            Until::Done => false,
+            Until::HomeSwitch => self.home_switch_active(),
+            Until::Stall(armed_below) => turn_steps.count() <= armed_below && self.stalled(),
+        }
+    }
+
     fn calc_sleep(&self) -> (Duration, Duration) {
         if let Some(last) = self.last {
             let now = Instant::now();
             let lateness = now.saturating_duration_since(last);
-            return Self::positive_or_zero(consts::MAX_SPEED as i64 - lateness.as_micros() as i64 - self.hanger.as_micros() as i64);
+            return Self::positive_or_zero(self.tick.as_micros() as i64 - lateness.as_micros() as i64 - self.hanger.as_micros() as i64);
         } else {
-            return (Duration::from_micros(consts::MAX_SPEED), Duration::from_micros(0));
+            return (self.tick, Duration::from_micros(0));
         }
     }
 
@@ -236,11 +569,19 @@ impl Motor {
     }
 }
 
-#[embassy_executor::task]
+// every motor runs in its own task, the pools allow up to 4 of them at once
+#[embassy_executor::task(pool_size = 4)]
 pub async fn global_turn(mut stepper: Motor, turn_steps: TurnSteps, ticks: Ticks) -> () {
     if let Err(e) = stepper.start_turning(turn_steps, ticks).await {
         panic!("State error {:?}", e);
-    } else {
-        ()
     }
-} 
+    stepper.idle().await;
+}
+
+#[embassy_executor::task(pool_size = 4)]
+pub async fn global_profiled_turn(mut stepper: Motor, turn_steps: TurnSteps, profile: Profile) -> () {
+    if let Err(e) = stepper.start_profiled(turn_steps, profile).await {
+        panic!("State error {:?}", e);
+    }
+    stepper.idle().await;
+}
@@ -0,0 +1,87 @@
+#[cfg(feature = "rp2040")]
+use embassy_rp::gpio::{AnyPin, Input, Output, Pin};
+
+/// Pins a `Motor` is built on unless told otherwise.
+#[cfg(feature = "rp2040")]
+pub type DefaultOut = Output<'static, AnyPin>;
+#[cfg(feature = "rp2040")]
+pub type DefaultIn = Input<'static, AnyPin>;
+
+// there's no gpio off the chip, the tasks still build so the core can be tested on the host
+#[cfg(not(feature = "rp2040"))]
+pub type DefaultOut = NoPin;
+#[cfg(not(feature = "rp2040"))]
+pub type DefaultIn = NoPin;
+
+/// Output line the motor drives (step, dir, sleep), implemented for rp2040 outputs and `mock::RecordingPin`.
+pub trait DigitalOut {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+    fn toggle(&mut self);
+    fn is_set_low(&self) -> bool;
+}
+
+#[cfg(feature = "rp2040")]
+impl<'d, T: Pin> DigitalOut for Output<'d, T> {
+    fn set_high(&mut self) {
+        Output::set_high(self)
+    }
+
+    fn set_low(&mut self) {
+        Output::set_low(self)
+    }
+
+    fn toggle(&mut self) {
+        Output::toggle(self)
+    }
+
+    fn is_set_low(&self) -> bool {
+        Output::is_set_low(self)
+    }
+}
+
+/// Input line the motor watches (limit switches), implemented for rp2040 inputs and `mock::MockInput`.
+pub trait DigitalIn {
+    fn is_high(&self) -> bool;
+
+    fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+#[cfg(feature = "rp2040")]
+impl<'d, T: Pin> DigitalIn for Input<'d, T> {
+    fn is_high(&self) -> bool {
+        Input::is_high(self)
+    }
+}
+
+/// Pin that can't exist, the default pins of builds without the rp2040.
+#[cfg(not(feature = "rp2040"))]
+pub enum NoPin {}
+
+#[cfg(not(feature = "rp2040"))]
+impl DigitalOut for NoPin {
+    fn set_high(&mut self) {
+        match *self {}
+    }
+
+    fn set_low(&mut self) {
+        match *self {}
+    }
+
+    fn toggle(&mut self) {
+        match *self {}
+    }
+
+    fn is_set_low(&self) -> bool {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "rp2040"))]
+impl DigitalIn for NoPin {
+    fn is_high(&self) -> bool {
+        match *self {}
+    }
+}
@@ -0,0 +1,162 @@
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::{AnyPin, Output};
+use embassy_rp::pio::{Common, Config, Direction, Instance, PioPin, StateMachine};
+use fixed::traits::ToFixed;
+use fixed::types::U56F8;
+
+use super::pin::DigitalOut;
+use super::planner::{Planner, Profile};
+use super::{MotorParams, StateError, Ticks, TurnSteps};
+
+const SM_FREQUENCY: u32 = 1_000_000; // one state machine cycle per microsecond
+const CYCLES_PER_STEP: u64 = 6; // instructions outside of the two delay loops
+const MAX_IN_FLIGHT: usize = 3; // one segment running, two waiting in the 4 word tx fifo
+
+/// Motor backend where a PIO state machine generates the step pulses.
+///
+/// The CPU pushes segments of `(delay, steps - 1)` into the tx fifo and the state machine emits exactly
+/// that many pulses, then pushes a word to the rx fifo to tell the segment is done.
+/// Direction and sleep are still plain outputs.
+pub struct PioMotor<'d, PIO: Instance, const SM: usize, P: DigitalOut = Output<'static, AnyPin>> {
+    pub params: MotorParams,
+    sm: StateMachine<'d, PIO, SM>,
+    dir_pin_ot: P,
+    sleep_pin_ot: P,
+    in_flight: usize,
+}
+
+impl<'d, PIO: Instance, const SM: usize, P: DigitalOut> PioMotor<'d, PIO, SM, P> {
+    pub fn new(
+        params: MotorParams,
+        common: &mut Common<'d, PIO>,
+        mut sm: StateMachine<'d, PIO, SM>,
+        step_pin: impl PioPin,
+        dir_ot: P,
+        mut sleep_ot: P,
+    ) -> Self {
+        let prg = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "    pull block     side 0", // delay loop count
+            "    mov x, osr     side 0",
+            "    pull block     side 0", // steps - 1
+            "    mov y, osr     side 0",
+            "step:",
+            "    mov isr, x     side 1",
+            "high:",
+            "    jmp x-- high   side 1",
+            "    mov x, isr     side 0",
+            "low:",
+            "    jmp x-- low    side 0",
+            "    mov x, isr     side 0",
+            "    jmp y-- step   side 0",
+            "    push block     side 0", // segment done
+            ".wrap",
+        );
+
+        let step_pin = common.make_pio_pin(step_pin);
+        sm.set_pin_dirs(Direction::Out, &[&step_pin]);
+
+        let mut cfg = Config::default();
+        cfg.use_program(&common.load_program(&prg.program), &[&step_pin]);
+        // whatever the system clock was set up to, not just the 125 MHz default
+        cfg.clock_divider = (U56F8::from_num(clk_sys_freq()) / U56F8::from_num(SM_FREQUENCY)).to_fixed();
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        sleep_ot.set_low();
+
+        Self {
+            params,
+            sm,
+            dir_pin_ot: dir_ot,
+            sleep_pin_ot: sleep_ot,
+            in_flight: 0,
+        }
+    }
+
+    pub async fn toggle_dir(&mut self) -> Result<(), StateError> {
+        if self.in_flight != 0 {
+            return Err(StateError::AlreadyTurning)
+        }
+        self.dir_pin_ot.toggle();
+        Ok(())
+    }
+
+    /// Same step period as `Motor::start_turning`, `ticks - 1` ticks of `max_speed` microseconds,
+    /// and refused the same way below 2 ticks, see `MotorParams::check_ticks`.
+    pub async fn start_turning(&mut self, turn_steps: TurnSteps, ticks_per_step: Ticks) -> Result<(), StateError> {
+        self.params.check_ticks(ticks_per_step)?;
+
+        let interval = (ticks_per_step.count() - 1) as u64 * self.params.max_speed;
+
+        self.wake();
+        self.push_segment(interval, turn_steps.count()).await;
+        self.finish().await;
+
+        Ok(())
+    }
+
+    /// Every step is its own single pulse segment, the fifo keeps the state machine busy while the next interval is planned.
+    pub async fn start_profiled(&mut self, turn_steps: TurnSteps, profile: impl Into<Profile>) -> Result<(), StateError> {
+        let profile = profile.into();
+        if let Profile::Constant(ticks) = profile {
+            return self.start_turning(turn_steps, ticks).await;
+        }
+
+        if !profile.is_valid() {
+            return Err(StateError::InvalidProfile);
+        }
+        self.params.check_profile(&profile)?;
+
+        if let Some(interval) = profile.interval() {
+            let interval = interval.max(self.params.max_speed);
+
+            self.wake();
+            self.push_segment(interval, turn_steps.count()).await;
+            self.finish().await;
+            return Ok(());
+        }
+
+        let mut planner = Planner::new(profile, turn_steps.count());
+
+        self.wake();
+        while let Some(interval) = planner.next_interval() {
+            self.push_segment(interval.max(self.params.max_speed), 1).await;
+        }
+        self.finish().await;
+
+        Ok(())
+    }
+
+    fn wake(&mut self) {
+        if self.sleep_pin_ot.is_set_low() {
+            self.sleep_pin_ot.set_high();
+        }
+    }
+
+    async fn push_segment(&mut self, interval: u64, steps: u32) {
+        if steps == 0 {
+            return;
+        }
+
+        if self.in_flight == MAX_IN_FLIGHT {
+            self.sm.rx().wait_pull().await;
+            self.in_flight -= 1;
+        }
+
+        let delay = interval.saturating_sub(CYCLES_PER_STEP) / 2;
+        self.sm.tx().wait_push(delay as u32).await;
+        self.sm.tx().wait_push(steps - 1).await;
+        self.in_flight += 1;
+    }
+
+    async fn finish(&mut self) {
+        while self.in_flight > 0 {
+            self.sm.rx().wait_pull().await;
+            self.in_flight -= 1;
+        }
+
+        self.sleep_pin_ot.set_low();
+    }
+}
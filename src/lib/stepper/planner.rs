@@ -0,0 +1,124 @@
+use super::ramp::{TrapezoidalProfile, TrapezoidalRamp};
+use super::scurve::{SCurveProfile, SCurveRamp};
+use super::Ticks;
+
+/// Shape of a move's velocity over time.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Profile {
+    Constant(Ticks),
+    /// Constant velocity in steps per second.
+    Velocity(f32),
+    Trapezoidal(TrapezoidalProfile),
+    SCurve(SCurveProfile),
+}
+
+impl Profile {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Constant(t) => t.count() >= 1,
+            Self::Velocity(v) => *v > 0.0,
+            Self::Trapezoidal(p) => p.is_valid(),
+            Self::SCurve(p) => p.is_valid(),
+        }
+    }
+
+    /// Fastest the profile ever goes, in steps per second, `None` for `Constant` which runs at the motor's limit.
+    pub(crate) fn max_velocity(&self) -> Option<f32> {
+        match self {
+            Self::Constant(_) => None,
+            Self::Velocity(v) => Some(*v),
+            Self::Trapezoidal(p) => Some(p.max_velocity),
+            Self::SCurve(p) => Some(p.max_velocity),
+        }
+    }
+
+    /// Step interval in microseconds of a `Velocity` profile.
+    pub(crate) fn interval(&self) -> Option<u64> {
+        match self {
+            Self::Velocity(v) => Some((1_000_000.0 / v) as u64),
+            _ => None,
+        }
+    }
+}
+
+impl From<Ticks> for Profile {
+    fn from(ticks: Ticks) -> Self {
+        Self::Constant(ticks)
+    }
+}
+
+impl From<TrapezoidalProfile> for Profile {
+    fn from(profile: TrapezoidalProfile) -> Self {
+        Self::Trapezoidal(profile)
+    }
+}
+
+impl From<SCurveProfile> for Profile {
+    fn from(profile: SCurveProfile) -> Self {
+        Self::SCurve(profile)
+    }
+}
+
+/// Per-step interval source of a running move, `Constant` keeps the tick set before the move.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub(crate) enum Planner {
+    Constant,
+    Trapezoidal(TrapezoidalRamp),
+    SCurve(SCurveRamp),
+}
+
+impl Planner {
+    pub(crate) fn new(profile: Profile, steps: u32) -> Self {
+        match profile {
+            Profile::Constant(_) | Profile::Velocity(_) => Self::Constant,
+            Profile::Trapezoidal(p) => Self::Trapezoidal(TrapezoidalRamp::new(p, steps)),
+            Profile::SCurve(p) => Self::SCurve(SCurveRamp::new(p, steps)),
+        }
+    }
+
+    pub(crate) fn next_interval(&mut self) -> Option<u64> {
+        match self {
+            Self::Constant => None,
+            Self::Trapezoidal(r) => r.next_interval(),
+            Self::SCurve(r) => r.next_interval(),
+        }
+    }
+
+    /// Steps left in the plan, `None` when the plan doesn't count steps.
+    pub(crate) fn remaining(&self) -> Option<u32> {
+        match self {
+            Self::Constant => None,
+            Self::Trapezoidal(r) => Some(r.remaining()),
+            Self::SCurve(r) => Some(r.remaining()),
+        }
+    }
+
+    /// Starts decelerating to a standstill, returns `false` when the plan can only stop at once.
+    pub(crate) fn stop(&mut self) -> bool {
+        match self {
+            Self::Constant => false,
+            Self::Trapezoidal(r) => {
+                r.stop();
+                true
+            }
+            Self::SCurve(r) => {
+                r.stop();
+                true
+            }
+        }
+    }
+
+    /// Changes the cruise velocity of the rest of the move, returns `false` when the plan doesn't time the
+    /// steps and the tick has to change instead.
+    pub(crate) fn set_speed(&mut self, velocity: f32) -> bool {
+        match self {
+            Self::Constant => false,
+            Self::Trapezoidal(r) => {
+                r.set_max_velocity(velocity);
+                true
+            }
+            // can't be retimed halfway, `MotionControl::set_speed` refuses it and a late one is dropped
+            Self::SCurve(_) => true,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use heapless::Deque;
+
+use super::axes::Axes;
+use super::pin::{DigitalIn, DigitalOut};
+use super::ramp::{sqrt, TrapezoidalProfile};
+use super::StateError;
+
+/// Corners sharper than this (cosine of the angle between segments) are taken from a standstill.
+const REVERSAL_COS: f32 = 0.999_999;
+
+#[derive(Copy, Clone, Debug)]
+struct Segment<const N: usize> {
+    target: [i32; N],
+    /// Euclidean length in steps.
+    length: f32,
+    /// Steps of the longest axis per step of path length.
+    major_ratio: f32,
+    nominal: f32,
+    max_entry: f32,
+    entry: f32,
+}
+
+/// Bounded queue of straight moves whose entry and exit speeds are planned across segments
+/// (junction deviation, the same way grbl does it), so a path only stops where it has to.
+///
+/// Speeds and acceleration are along the path, in steps per second (and steps/s^2).
+pub struct MotionQueue<const N: usize, const Q: usize> {
+    segments: Deque<Segment<N>, Q>,
+    /// Where the last queued segment ends.
+    end: [i32; N],
+    last_unit: Option<[f32; N]>,
+    /// Speed the front segment is entered at, the exit speed of the segment that ran before it.
+    speed: f32,
+    pub acceleration: f32,
+    pub junction_deviation: f32,
+}
+
+impl<const N: usize, const Q: usize> MotionQueue<N, Q> {
+    pub fn new(start: [i32; N], acceleration: f32, junction_deviation: f32) -> Self {
+        Self {
+            segments: Deque::new(),
+            end: start,
+            last_unit: None,
+            speed: 0.0,
+            acceleration,
+            junction_deviation,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.segments.is_full()
+    }
+
+    /// Queues a move to `target` at path speed `velocity`, refused with `StateError::QueueFull` while the queue is full
+    /// and with `StateError::InvalidProfile` unless the speed is positive.
+    pub fn push(&mut self, target: [i32; N], velocity: f32) -> Result<(), StateError> {
+        if !velocity.is_finite() || velocity <= 0.0 {
+            return Err(StateError::InvalidProfile);
+        }
+        if self.segments.is_full() {
+            return Err(StateError::QueueFull);
+        }
+
+        let mut length = 0.0;
+        let mut major = 0;
+        let mut unit = [0.0; N];
+        for ((component, to), from) in unit.iter_mut().zip(target).zip(self.end) {
+            let delta = to - from;
+            *component = delta as f32;
+            length += *component * *component;
+            major = major.max(delta.unsigned_abs());
+        }
+        if major == 0 {
+            return Ok(());
+        }
+
+        let length = sqrt(length);
+        unit.iter_mut().for_each(|u| *u /= length);
+
+        let max_entry = match self.last_unit {
+            Some(last) if !self.segments.is_empty() => self.junction_speed(&last, &unit).min(velocity),
+            _ => 0.0,
+        };
+
+        let _ = self.segments.push_back(Segment {
+            target,
+            length,
+            major_ratio: major as f32 / length,
+            nominal: velocity,
+            max_entry,
+            entry: 0.0,
+        });
+        self.end = target;
+        self.last_unit = Some(unit);
+        self.replan();
+
+        Ok(())
+    }
+
+    /// Runs the front segment, the next one (if already queued) continues without stopping.
+    /// Returns `false` once the queue is empty, or when a `MotionControl` stop ended the segment and dropped the rest.
+    pub async fn run_one<P: DigitalOut, I: DigitalIn>(&mut self, axes: &mut Axes<P, I, N>) -> Result<bool, StateError> {
+        let segment = match self.segments.pop_front() {
+            Some(segment) => segment,
+            None => return Ok(false),
+        };
+        let exit = self.segments.front().map_or(0.0, |next| next.entry);
+
+        let ratio = segment.major_ratio;
+        let profile = TrapezoidalProfile::new(segment.nominal * ratio, self.acceleration * ratio, self.acceleration * ratio);
+
+        let result = axes.run(segment.target, &profile, self.speed * ratio, exit * ratio).await;
+        if result.is_err() || axes.stopped() {
+            self.clear(axes.position());
+            return result.map(|_| false);
+        }
+
+        self.speed = exit;
+        Ok(true)
+    }
+
+    /// Runs every queued segment.
+    pub async fn run<P: DigitalOut, I: DigitalIn>(&mut self, axes: &mut Axes<P, I, N>) -> Result<(), StateError> {
+        while self.run_one(axes).await? {}
+        Ok(())
+    }
+
+    /// Drops the queued segments, e.g. after a move failed and the machine stopped at `position`.
+    pub fn clear(&mut self, position: [i32; N]) {
+        self.segments.clear();
+        self.end = position;
+        self.last_unit = None;
+        self.speed = 0.0;
+    }
+
+    /// Fastest speed through the corner between two segments that keeps the path within `junction_deviation`.
+    fn junction_speed(&self, from: &[f32; N], to: &[f32; N]) -> f32 {
+        let cos = -from.iter().zip(to.iter()).map(|(a, b)| a * b).sum::<f32>();
+        if cos > REVERSAL_COS {
+            return 0.0;
+        }
+        if cos < -REVERSAL_COS {
+            return f32::MAX;
+        }
+
+        let sin_half = sqrt(0.5 * (1.0 - cos));
+        sqrt(self.acceleration * self.junction_deviation * sin_half / (1.0 - sin_half))
+    }
+
+    /// Backward pass lowers entries so every segment can still brake for the next one (and to a stop at the end),
+    /// forward pass lowers them where the previous segment can't accelerate that fast.
+    fn replan(&mut self) {
+        let accel = 2.0 * self.acceleration;
+
+        let mut exit = 0.0;
+        for segment in self.segments.iter_mut().rev() {
+            segment.entry = segment.max_entry.min(sqrt(exit * exit + accel * segment.length));
+            exit = segment.entry;
+        }
+
+        let mut entry = self.speed;
+        let mut first = true;
+        for segment in self.segments.iter_mut() {
+            if first {
+                segment.entry = entry;
+                first = false;
+            } else if segment.entry > entry {
+                segment.entry = entry;
+            }
+            entry = sqrt(segment.entry * segment.entry + accel * segment.length).min(segment.nominal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: f32, expected: f32, tolerance: f32) -> bool {
+        actual >= expected * (1.0 - tolerance) && actual <= expected * (1.0 + tolerance)
+    }
+
+    /// 1000 steps/s^2 along the path, 0.05 steps of junction deviation.
+    fn queue() -> MotionQueue<2, 4> {
+        MotionQueue::new([0, 0], 1000.0, 0.05)
+    }
+
+    fn entries(queue: &MotionQueue<2, 4>) -> [f32; 2] {
+        let mut entries = queue.segments.iter().map(|segment| segment.entry);
+        [entries.next().unwrap(), entries.next().unwrap()]
+    }
+
+    #[test]
+    fn straight_continuation_keeps_nominal_speed() {
+        let mut queue = queue();
+        queue.push([100, 0], 200.0).unwrap();
+        queue.push([200, 0], 200.0).unwrap();
+
+        assert_eq!(entries(&queue), [0.0, 200.0]);
+    }
+
+    #[test]
+    fn right_angle_is_limited_by_junction_deviation() {
+        let mut queue = queue();
+        queue.push([100, 0], 200.0).unwrap();
+        queue.push([100, 100], 200.0).unwrap();
+
+        // sin(45°) = 0.7071, sqrt(1000 * 0.05 * 0.7071 / (1 - 0.7071))
+        let entry = entries(&queue)[1];
+        assert!(close(entry, 10.99, 0.01), "{}", entry);
+    }
+
+    #[test]
+    fn reversal_stops_in_between() {
+        let mut queue = queue();
+        queue.push([100, 0], 200.0).unwrap();
+        queue.push([0, 0], 200.0).unwrap();
+
+        assert_eq!(entries(&queue)[1], 0.0);
+    }
+
+    #[test]
+    fn last_segment_brakes_to_a_stop() {
+        let mut queue = queue();
+        queue.push([100, 0], 1000.0).unwrap();
+        queue.push([110, 0], 1000.0).unwrap();
+
+        // entered no faster than it can stop from in 10 steps, sqrt(2 * 1000 * 10)
+        let entry = entries(&queue)[1];
+        assert!(close(entry, 141.42, 0.01), "{}", entry);
+    }
+
+    #[test]
+    fn short_segment_caps_the_next_entry() {
+        let mut queue = queue();
+        queue.push([10, 0], 1000.0).unwrap();
+        queue.push([1000, 0], 1000.0).unwrap();
+
+        // only 10 steps to speed up from a standstill, sqrt(2 * 1000 * 10)
+        let entry = entries(&queue)[1];
+        assert!(close(entry, 141.42, 0.01), "{}", entry);
+    }
+
+    #[test]
+    fn push_refuses_speeds_that_arent_positive() {
+        let mut queue = queue();
+        for velocity in [0.0, -10.0, f32::NAN] {
+            assert_eq!(queue.push([10, 0], velocity), Err(StateError::InvalidProfile));
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn push_refuses_once_full() {
+        let mut queue = queue();
+        for x in 1..=4 {
+            queue.push([x * 10, 0], 100.0).unwrap();
+        }
+        assert_eq!(queue.push([50, 0], 100.0), Err(StateError::QueueFull));
+        assert_eq!(queue.len(), 4);
+    }
+}
@@ -0,0 +1,222 @@
+/// Velocity limits of a trapezoidal move, all in steps per second (or steps/s^2).
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TrapezoidalProfile {
+    pub max_velocity: f32,
+    pub acceleration: f32,
+    pub deceleration: f32,
+}
+
+impl TrapezoidalProfile {
+    pub fn new(max_velocity: f32, acceleration: f32, deceleration: f32) -> Self {
+        Self {
+            max_velocity,
+            acceleration,
+            deceleration,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.max_velocity > 0.0 && self.acceleration > 0.0 && self.deceleration > 0.0
+    }
+}
+
+/// Yields the interval (in microseconds) of every step of a trapezoidal move.
+///
+/// Velocity is looked up by position instead of time: `v(x) = min(vmax, sqrt(v0^2 + 2ax), sqrt(ve^2 + 2d(n - x)))`,
+/// and each step takes `2 / (v(x) + v(x + 1))` seconds, which is exact for constant acceleration.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TrapezoidalRamp {
+    profile: TrapezoidalProfile,
+    steps: u32,
+    step: u32,
+    entry: f32,
+    exit: f32,
+}
+
+impl TrapezoidalRamp {
+    pub fn new(profile: TrapezoidalProfile, steps: u32) -> Self {
+        Self::with_speeds(profile, steps, 0.0, 0.0)
+    }
+
+    /// Ramp that enters the move at `entry` and leaves it at `exit` steps per second.
+    /// An `entry` above the max velocity is decelerated down to it.
+    pub fn with_speeds(profile: TrapezoidalProfile, steps: u32, entry: f32, exit: f32) -> Self {
+        Self {
+            profile,
+            steps,
+            step: 0,
+            entry,
+            exit: exit.min(profile.max_velocity),
+        }
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.steps - self.step
+    }
+
+    pub fn velocity_at(&self, position: f32) -> f32 {
+        let entry = self.entry * self.entry;
+        let cruise = if self.entry > self.profile.max_velocity {
+            self.profile.max_velocity.max(sqrt(entry - 2.0 * self.profile.deceleration * position))
+        } else {
+            self.profile.max_velocity.min(sqrt(entry + 2.0 * self.profile.acceleration * position))
+        };
+        let decel = sqrt(self.exit * self.exit + 2.0 * self.profile.deceleration * (self.steps as f32 - position));
+
+        cruise.min(decel)
+    }
+
+    /// Velocity of the step about to be taken.
+    pub fn velocity(&self) -> f32 {
+        self.velocity_at(self.step as f32)
+    }
+
+    /// Shortens the move to the fewest steps that still decelerate to a standstill.
+    pub fn stop(&mut self) {
+        let v = self.velocity();
+        let stopping = (v * v / (2.0 * self.profile.deceleration)) as u32 + 1;
+
+        self.exit = 0.0;
+        if stopping < self.remaining() {
+            self.steps = self.step + stopping;
+        }
+    }
+
+    /// Replans the rest of the move for a new max velocity, starting from the current velocity.
+    pub fn set_max_velocity(&mut self, max_velocity: f32) {
+        let mut profile = self.profile;
+        profile.max_velocity = max_velocity;
+
+        *self = Self::with_speeds(profile, self.remaining(), self.velocity(), self.exit);
+    }
+
+    pub fn next_interval(&mut self) -> Option<u64> {
+        if self.step >= self.steps {
+            return None;
+        }
+
+        let position = self.step as f32;
+        let sum = self.velocity_at(position) + self.velocity_at(position + 1.0);
+        self.step += 1;
+
+        // single step moves start and end at standstill, speed peaks in the middle of the step
+        if sum <= f32::EPSILON {
+            return Some((2_000_000.0 / self.velocity_at(position + 0.5)) as u64);
+        }
+
+        Some((2_000_000.0 / sum) as u64)
+    }
+}
+
+impl Iterator for TrapezoidalRamp {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_interval()
+    }
+}
+
+/// `core` has no float square root on bare metal, bit-trick guess refined with Newton's method.
+pub(crate) fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1fbd_1df5);
+    for _ in 0..3 {
+        y = 0.5 * (y + x / y);
+    }
+
+    y
+}
+
+/// Nearest whole number, halves round away from zero (`f32::round` is std only too).
+pub(crate) fn round(x: f32) -> i32 {
+    if x < 0.0 {
+        (x - 0.5) as i32
+    } else {
+        (x + 0.5) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: f32, expected: f32, tolerance: f32) -> bool {
+        actual >= expected * (1.0 - tolerance) && actual <= expected * (1.0 + tolerance)
+    }
+
+    #[test]
+    fn sqrt_is_close_enough() {
+        for x in [0.25f32, 2.0, 10.0, 12345.0, 1e9] {
+            let y = sqrt(x);
+            assert!(close(y * y, x, 1e-5), "{}", x);
+        }
+        assert_eq!(sqrt(0.0), 0.0);
+        assert_eq!(sqrt(-4.0), 0.0);
+    }
+
+    #[test]
+    fn round_takes_halves_away_from_zero() {
+        assert_eq!(round(2.4), 2);
+        assert_eq!(round(2.5), 3);
+        assert_eq!(round(-2.4), -2);
+        assert_eq!(round(-2.5), -3);
+        assert_eq!(round(0.0), 0);
+    }
+
+    #[test]
+    fn single_step_takes_the_whole_ramp_up_and_down() {
+        // half a step accelerating at 1000 steps/s^2 and half decelerating, sqrt(2 * 0.5 / 1000) each
+        let mut ramp = TrapezoidalRamp::new(TrapezoidalProfile::new(1000.0, 1000.0, 1000.0), 1);
+        let interval = ramp.next_interval().unwrap();
+        assert!(close(interval as f32, 63_246.0, 0.01), "{}", interval);
+        assert_eq!(ramp.next_interval(), None);
+    }
+
+    #[test]
+    fn triangle_move_takes_twice_the_ramp_time() {
+        // 500 steps up to exactly 1000 steps/s and 500 back down, 1 s each
+        let ramp = TrapezoidalRamp::new(TrapezoidalProfile::new(1000.0, 1000.0, 1000.0), 1000);
+        let total: u64 = ramp.sum();
+        assert!(close(total as f32, 2_000_000.0, 0.01), "{}", total);
+    }
+
+    #[test]
+    fn cruises_at_max_velocity() {
+        let ramp = TrapezoidalRamp::new(TrapezoidalProfile::new(500.0, 1000.0, 1000.0), 1000);
+        let intervals: Vec<u64> = ramp.collect();
+        assert_eq!(intervals.len(), 1000);
+        assert_eq!(intervals[500], 2000);
+        assert!(intervals.iter().all(|&i| i >= 2000));
+        assert!(intervals[0] > intervals[1]);
+        assert!(intervals[999] > intervals[998]);
+    }
+
+    #[test]
+    fn stop_shortens_to_the_braking_distance() {
+        let mut ramp = TrapezoidalRamp::new(TrapezoidalProfile::new(500.0, 1000.0, 1000.0), 1000);
+        for _ in 0..300 {
+            ramp.next_interval();
+        }
+        ramp.stop();
+        // 500^2 / (2 * 1000) steps to come to a standstill
+        assert_eq!(ramp.remaining(), 126);
+        assert!(ramp.last().unwrap() > 10_000);
+    }
+
+    #[test]
+    fn new_max_velocity_takes_over_from_the_current_speed() {
+        let mut ramp = TrapezoidalRamp::new(TrapezoidalProfile::new(500.0, 1000.0, 1000.0), 1000);
+        for _ in 0..300 {
+            ramp.next_interval();
+        }
+        ramp.set_max_velocity(250.0);
+        assert_eq!(ramp.remaining(), 700);
+
+        let intervals: Vec<u64> = ramp.collect();
+        assert!(intervals[0] >= 2000 && intervals[0] < 4000);
+        assert_eq!(intervals[400], 4000);
+    }
+}
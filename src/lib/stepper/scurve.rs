@@ -0,0 +1,414 @@
+use super::ramp::sqrt;
+
+const MAX_ITERATIONS: usize = 32;
+const TOLERANCE: f64 = 1e-4; // in steps
+
+/// Limits of a jerk-limited move, in steps/s, steps/s^2 and steps/s^3.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SCurveProfile {
+    pub max_velocity: f32,
+    pub max_acceleration: f32,
+    pub max_jerk: f32,
+}
+
+impl SCurveProfile {
+    pub fn new(max_velocity: f32, max_acceleration: f32, max_jerk: f32) -> Self {
+        Self {
+            max_velocity,
+            max_acceleration,
+            max_jerk,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.max_velocity > 0.0 && self.max_acceleration > 0.0 && self.max_jerk > 0.0
+    }
+}
+
+/// Yields the interval (in microseconds) of every step of a seven phase S-curve move that starts and ends at rest.
+///
+/// The move is planned in time (jerk, constant acceleration and jerk-down phases, cruise, then the mirrored
+/// deceleration), every step time is found by inverting the position curve with a safeguarded Newton search.
+/// Math is done in `f64`, long moves would otherwise lose microseconds to rounding of the absolute time.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SCurveRamp {
+    steps: u32,
+    step: u32,
+    distance: f64,
+    jerk: f64,
+    accel: f64,
+    velocity: f64,
+    t_j: f64,
+    t_ca: f64,
+    t_a: f64,
+    total: f64,
+    time: f64,
+    braking: Option<Braking>,
+}
+
+/// Jerk-limited deceleration to a standstill from wherever `SCurveRamp::stop` caught the move: acceleration
+/// ramps down from what it was to the peak deceleration, stays there, then ramps back up to zero.
+#[derive(PartialEq, Copy, Clone, Debug)]
+struct Braking {
+    start: f64,
+    position: f64,
+    velocity: f64,
+    accel: f64,
+    t_down: f64,
+    t_const: f64,
+    t_up: f64,
+}
+
+impl Braking {
+    fn new(start: f64, position: f64, velocity: f64, accel: f64, max_decel: f64, jerk: f64) -> Self {
+        // velocity reached once the acceleration is ramped down, has to be shed like from a cruise
+        let reach = velocity + accel * accel / (2.0 * jerk);
+        let (decel, t_const) = if reach * jerk >= max_decel * max_decel {
+            (max_decel, (reach - max_decel * max_decel / jerk) / max_decel)
+        } else {
+            (sqrt64(reach * jerk), 0.0)
+        };
+
+        Self {
+            start,
+            position,
+            velocity,
+            accel,
+            t_down: (accel + decel) / jerk,
+            t_const,
+            t_up: decel / jerk,
+        }
+    }
+
+    fn duration(&self) -> f64 {
+        self.t_down + self.t_const + self.t_up
+    }
+
+    /// Position and velocity `t` seconds after braking started.
+    fn state_at(&self, t: f64, jerk: f64) -> (f64, f64) {
+        let (mut s, mut v, mut a) = (self.position, self.velocity, self.accel);
+        let mut left = t.max(0.0);
+
+        for (duration, j) in [(self.t_down, -jerk), (self.t_const, 0.0), (self.t_up, jerk)] {
+            let u = left.min(duration);
+            s += v * u + a * u * u / 2.0 + j * u * u * u / 6.0;
+            v += a * u + j * u * u / 2.0;
+            a += j * u;
+            left -= u;
+        }
+
+        (s, v.max(0.0))
+    }
+}
+
+impl SCurveRamp {
+    pub fn new(profile: SCurveProfile, steps: u32) -> Self {
+        let distance = steps as f64;
+        let jerk = profile.max_jerk as f64;
+        let accel = profile.max_acceleration as f64;
+
+        // lower the peak velocity until accelerating and decelerating fit into the distance
+        let mut velocity = profile.max_velocity as f64;
+        if velocity * Self::accel_time(velocity, accel, jerk) > distance {
+            let (mut lo, mut hi) = (0.0, velocity);
+            for _ in 0..MAX_ITERATIONS {
+                let mid = (lo + hi) / 2.0;
+                if mid * Self::accel_time(mid, accel, jerk) > distance {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            velocity = lo;
+        }
+
+        let (t_j, t_ca) = Self::phases(velocity, accel, jerk);
+        let t_a = 2.0 * t_j + t_ca;
+        let t_v = if velocity > 0.0 { (distance - velocity * t_a) / velocity } else { 0.0 };
+
+        Self {
+            steps,
+            step: 0,
+            distance,
+            jerk,
+            accel,
+            velocity,
+            t_j,
+            t_ca,
+            t_a,
+            total: 2.0 * t_a + t_v.max(0.0),
+            time: 0.0,
+            braking: None,
+        }
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.steps - self.step
+    }
+
+    /// Total duration of the move in seconds.
+    pub fn duration(&self) -> f64 {
+        self.total
+    }
+
+    /// Brakes to a standstill from the last step on, within the jerk and acceleration limits.
+    /// Caught while accelerating, the speed still rises a little until the acceleration is ramped down.
+    pub fn stop(&mut self) {
+        if self.braking.is_some() || self.time >= self.total - self.t_a {
+            return;
+        }
+
+        let (position, velocity) = self.state_at(self.time);
+        let accel = self.acceleration_at(self.time);
+        let braking = Braking::new(self.time, position, velocity, accel, self.accel, self.jerk);
+        let (distance, _) = braking.state_at(braking.duration(), self.jerk);
+        if distance >= self.distance {
+            return;
+        }
+
+        self.braking = Some(braking);
+        self.distance = distance;
+        self.steps = self.step.max(distance as u32);
+        self.total = self.time + braking.duration();
+    }
+
+    pub fn next_interval(&mut self) -> Option<u64> {
+        if self.step >= self.steps {
+            return None;
+        }
+
+        self.step += 1;
+        let next = if self.step == self.steps {
+            self.total
+        } else {
+            self.time_at(self.step as f64)
+        };
+
+        let interval = next - self.time;
+        self.time = next;
+
+        Some((interval * 1_000_000.0) as u64)
+    }
+
+    /// Jerk and constant acceleration phase durations needed to reach `velocity` from rest.
+    fn phases(velocity: f64, accel: f64, jerk: f64) -> (f64, f64) {
+        if velocity * jerk >= accel * accel {
+            let t_j = accel / jerk;
+            (t_j, velocity / accel - t_j)
+        } else {
+            (sqrt64(velocity / jerk), 0.0)
+        }
+    }
+
+    fn accel_time(velocity: f64, accel: f64, jerk: f64) -> f64 {
+        let (t_j, t_ca) = Self::phases(velocity, accel, jerk);
+        2.0 * t_j + t_ca
+    }
+
+    /// Position and velocity `t` seconds into the acceleration phase.
+    fn accelerating(&self, t: f64) -> (f64, f64) {
+        let j = self.jerk;
+        let a = j * self.t_j;
+
+        if t <= self.t_j {
+            return (j * t * t * t / 6.0, j * t * t / 2.0);
+        }
+
+        let s1 = j * self.t_j * self.t_j * self.t_j / 6.0;
+        let v1 = j * self.t_j * self.t_j / 2.0;
+        if t <= self.t_j + self.t_ca {
+            let u = t - self.t_j;
+            return (s1 + v1 * u + a * u * u / 2.0, v1 + a * u);
+        }
+
+        let s2 = s1 + v1 * self.t_ca + a * self.t_ca * self.t_ca / 2.0;
+        let v2 = v1 + a * self.t_ca;
+        let u = t - self.t_j - self.t_ca;
+        (s2 + v2 * u + a * u * u / 2.0 - j * u * u * u / 6.0, v2 + a * u - j * u * u / 2.0)
+    }
+
+    /// Acceleration `t` seconds into the move, before deceleration.
+    fn acceleration_at(&self, t: f64) -> f64 {
+        let peak = self.jerk * self.t_j;
+        if t <= self.t_j {
+            self.jerk * t
+        } else if t <= self.t_j + self.t_ca {
+            peak
+        } else if t < self.t_a {
+            peak - self.jerk * (t - self.t_j - self.t_ca)
+        } else {
+            0.0
+        }
+    }
+
+    /// Position and velocity `t` seconds into the whole move.
+    pub fn state_at(&self, t: f64) -> (f64, f64) {
+        if let Some(braking) = &self.braking {
+            if t >= braking.start {
+                return braking.state_at(t - braking.start, self.jerk);
+            }
+        }
+
+        if t <= self.t_a {
+            return self.accelerating(t);
+        }
+
+        // braking always starts before the planned deceleration would
+        if self.braking.is_some() || t <= self.total - self.t_a {
+            let (s_a, _) = self.accelerating(self.t_a);
+            return (s_a + self.velocity * (t - self.t_a), self.velocity);
+        }
+
+        let (s, v) = self.accelerating((self.total - t).max(0.0));
+        (self.distance - s, v)
+    }
+
+    fn time_at(&self, position: f64) -> f64 {
+        let (mut lo, mut hi) = (self.time, self.total);
+        let mut t = self.time;
+
+        for _ in 0..MAX_ITERATIONS {
+            let (s, v) = self.state_at(t);
+            let err = s - position;
+            if err < TOLERANCE && err > -TOLERANCE {
+                break;
+            }
+
+            if err > 0.0 {
+                hi = t;
+            } else {
+                lo = t;
+            }
+
+            let next = if v > 0.0 { t - err / v } else { lo };
+            t = if next <= lo || next >= hi { (lo + hi) / 2.0 } else { next };
+        }
+
+        t
+    }
+}
+
+impl Iterator for SCurveRamp {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_interval()
+    }
+}
+
+fn sqrt64(x: f64) -> f64 {
+    let y = sqrt(x as f32) as f64;
+    if y <= 0.0 {
+        return 0.0;
+    }
+
+    0.5 * (y + x / y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0.1 s of jerk up to 2000 steps/s^2, 0.3 s to reach 1000 steps/s: 0.6 s and 300 steps of ramp
+    fn profile() -> SCurveProfile {
+        SCurveProfile::new(1000.0, 2000.0, 20_000.0)
+    }
+
+    fn velocities(intervals: &[u64]) -> Vec<f64> {
+        intervals.iter().map(|&i| 1e6 / i as f64).collect()
+    }
+
+    fn peak(velocities: &[f64]) -> f64 {
+        velocities.iter().cloned().fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn takes_every_step_in_the_planned_time() {
+        let ramp = SCurveRamp::new(profile(), 2000);
+        // 1400 steps of cruise on top of the ramps
+        assert!((ramp.duration() - 2.6).abs() < 1e-3, "{}", ramp.duration());
+
+        let intervals: Vec<u64> = ramp.collect();
+        assert_eq!(intervals.len(), 2000);
+        let total: u64 = intervals.iter().sum();
+        assert!((total as f64 - 2.6e6).abs() < 2_000.0, "{}", total);
+    }
+
+    #[test]
+    fn never_goes_faster_than_max_velocity() {
+        let intervals: Vec<u64> = SCurveRamp::new(profile(), 2000).collect();
+        assert!(intervals.iter().all(|&i| i >= 999));
+        assert_eq!(intervals[1000], 1000);
+    }
+
+    #[test]
+    fn ramps_are_mirrored() {
+        let intervals: Vec<u64> = SCurveRamp::new(profile(), 2000).collect();
+        for i in 1..300 {
+            let (up, down) = (intervals[i] as f64, intervals[1999 - i] as f64);
+            assert!((up - down).abs() <= up * 0.02 + 2.0, "{} {} {}", i, up, down);
+        }
+    }
+
+    #[test]
+    fn short_move_lowers_the_peak() {
+        let velocities = velocities(&SCurveRamp::new(profile(), 100).collect::<Vec<u64>>());
+        assert_eq!(velocities.len(), 100);
+        assert!(peak(&velocities) < 600.0);
+    }
+
+    #[test]
+    fn stop_while_accelerating_brakes_from_the_current_speed() {
+        let mut ramp = SCurveRamp::new(profile(), 2000);
+        let before: Vec<u64> = (&mut ramp).take(50).collect();
+        let at_stop = 1e6 / *before.last().unwrap() as f64;
+
+        ramp.stop();
+        let after = velocities(&ramp.collect::<Vec<u64>>());
+
+        // the acceleration of about 2000 steps/s^2 takes 0.1 s to ramp down, adding 100 steps/s at most
+        assert!(peak(&after) < at_stop + 110.0, "{} {}", at_stop, peak(&after));
+        assert!(peak(&after) < 700.0);
+        assert!(after.len() < 200, "{}", after.len());
+        assert!(*after.last().unwrap() < 100.0);
+    }
+
+    #[test]
+    fn stop_while_cruising_mirrors_the_ramp_up() {
+        let mut ramp = SCurveRamp::new(profile(), 2000);
+        for _ in 0..1000 {
+            ramp.next_interval();
+        }
+
+        ramp.stop();
+        assert!(ramp.remaining() >= 299 && ramp.remaining() <= 301, "{}", ramp.remaining());
+        let after = velocities(&ramp.collect::<Vec<u64>>());
+        assert!(peak(&after) <= 1001.0);
+    }
+
+    #[test]
+    fn stop_while_decelerating_changes_nothing() {
+        let mut ramp = SCurveRamp::new(profile(), 2000);
+        for _ in 0..1800 {
+            ramp.next_interval();
+        }
+
+        let planned = ramp;
+        ramp.stop();
+        assert_eq!(ramp, planned);
+    }
+
+    #[test]
+    fn second_stop_changes_nothing() {
+        let mut ramp = SCurveRamp::new(profile(), 2000);
+        for _ in 0..50 {
+            ramp.next_interval();
+        }
+        ramp.stop();
+        ramp.next_interval();
+
+        let braking = ramp;
+        ramp.stop();
+        assert_eq!(ramp, braking);
+    }
+}
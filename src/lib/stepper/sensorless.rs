@@ -0,0 +1,124 @@
+use embedded_io_async::{Read, Write};
+
+use super::consts;
+use super::pin::{DigitalIn, DigitalOut};
+use super::tmc2209::{Tmc2209, SGTHRS, TCOOLTHRS};
+use super::{Direction, Motor, StateError, Ticks, TurnSteps, Until};
+
+/// Homing against a hard stop, the TMC2209 raises DIAG when StallGuard sees the motor stall.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SensorlessHoming {
+    pub direction: Direction,
+    /// StallGuard only works above a minimum speed, too slow and every step looks like a stall.
+    pub speed: Ticks,
+    /// SGTHRS, higher is more sensitive.
+    pub threshold: u8,
+    /// Steps DIAG is ignored for while the motor gets going.
+    pub blanking: u32,
+    /// Steps to back away from the stop after it was found.
+    pub backoff: u32,
+    pub max_travel: u32,
+}
+
+impl SensorlessHoming {
+    pub fn new(direction: Direction, speed: Ticks, threshold: u8, blanking: u32, backoff: u32, max_travel: u32) -> Self {
+        Self {
+            direction,
+            speed,
+            threshold,
+            blanking,
+            backoff,
+            max_travel,
+        }
+    }
+}
+
+impl Default for SensorlessHoming {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Backward,
+            speed: Ticks(consts::HOMING_TICKS),
+            threshold: consts::STALL_THRESHOLD,
+            blanking: consts::STALL_BLANKING,
+            backoff: consts::HOMING_BACKOFF,
+            max_travel: consts::HOMING_MAX_TRAVEL,
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct HomingResult {
+    /// Where the stall was detected, counted in the positions from before homing.
+    pub end_position: i32,
+    /// Steps taken from the start until the stall.
+    pub travel: u32,
+    /// Position after backing off, the stop itself is at the home offset.
+    pub position: i32,
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    pub fn with_stall_diag(mut self, diag: I, homing: SensorlessHoming) -> Self {
+        self.diag_in = Some(diag);
+        self.sensorless = homing;
+        self
+    }
+
+    pub fn set_stall_threshold(&mut self, threshold: u8) {
+        self.sensorless.threshold = threshold;
+    }
+
+    pub fn stalled(&self) -> bool {
+        match &self.diag_in {
+            Some(diag) => diag.is_high(),
+            None => false,
+        }
+    }
+
+    /// Runs into the stop until DIAG goes high, sets the position to the home offset there and backs off.
+    /// StallGuard has to be set up already (`TmcConfig::stall_threshold` and `coolstep_threshold`).
+    pub async fn home_sensorless(&mut self) -> Result<HomingResult, StateError> {
+        if self.diag_in.is_none() {
+            return Err(StateError::NoHomeSwitch);
+        }
+
+        let homing = self.sensorless;
+
+        self.set_dir(homing.direction).await?;
+        let armed_below = homing.max_travel.saturating_sub(homing.blanking);
+        let left = self.turn_constant(TurnSteps(homing.max_travel), homing.speed, Until::Stall(armed_below)).await?;
+
+        if !self.stalled() {
+            return Err(StateError::HomeNotFound);
+        }
+
+        let end_position = self.position;
+        self.position = self.home_offset;
+        self.sync_encoder();
+
+        // same as with a switch, limits only make sense once homed
+        self.set_dir(homing.direction.reversed()).await?;
+        self.turn_constant(TurnSteps(homing.backoff), homing.speed, Until::Done).await?;
+
+        Ok(HomingResult {
+            end_position,
+            travel: homing.max_travel - left.count(),
+            position: self.position,
+        })
+    }
+
+    /// Writes the homing threshold to the driver and makes sure DIAG is active at any speed for the move,
+    /// the previous TCOOLTHRS (`Tmc2209::coolstep_threshold`, it's write-only) is put back afterwards.
+    pub async fn home_sensorless_tmc<U: Read + Write>(&mut self, tmc: &mut Tmc2209<U>) -> Result<HomingResult, StateError> {
+        let coolstep = tmc.coolstep_threshold();
+        tmc.write_verified(SGTHRS, self.sensorless.threshold as u32).await.map_err(|_| StateError::DriverComm)?;
+        tmc.write_verified(TCOOLTHRS, 0xF_FFFF).await.map_err(|_| StateError::DriverComm)?;
+
+        let result = self.home_sensorless().await;
+        let restored = tmc.write_verified(TCOOLTHRS, coolstep).await;
+
+        // why homing failed matters more than the restore failing too
+        let homed = result?;
+        restored.map_err(|_| StateError::DriverComm)?;
+        Ok(homed)
+    }
+}
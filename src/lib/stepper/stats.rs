@@ -0,0 +1,101 @@
+use embassy_time::{Duration, Instant};
+
+use crate::rlog::color::Color;
+use crate::rlog::log_fmt;
+use crate::rlogf;
+
+use super::pin::{DigitalIn, DigitalOut};
+use super::Motor;
+
+/// Timer wake-ups at most this late still count as on time, the timer itself isn't any finer.
+const LATE_MARGIN: Duration = Duration::from_micros(20);
+
+/// Step timing of the last move, intervals are measured between rising edges of the step pin.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TimingStats {
+    pub steps: u32,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// Sum of all intervals, see `mean_interval`.
+    pub total: Duration,
+    /// Steps taken later than `LATE_MARGIN` after they were due.
+    pub late: u32,
+    pub worst_lateness: Duration,
+    last_step: Option<Instant>,
+    lateness: Duration,
+}
+
+impl TimingStats {
+    pub const fn new() -> Self {
+        Self {
+            steps: 0,
+            min_interval: Duration::MAX,
+            max_interval: Duration::from_micros(0),
+            total: Duration::from_micros(0),
+            late: 0,
+            worst_lateness: Duration::from_micros(0),
+            last_step: None,
+            lateness: Duration::from_micros(0),
+        }
+    }
+
+    pub fn mean_interval(&self) -> Duration {
+        match self.steps {
+            0 | 1 => Duration::from_micros(0),
+            n => self.total / (n - 1),
+        }
+    }
+
+    /// After a sleep that should have ended at `due`, `owed` is the lateness `calc_sleep` couldn't make up.
+    pub(crate) fn woke(&mut self, due: Instant, owed: Duration) {
+        self.lateness = Instant::now().saturating_duration_since(due) + owed;
+    }
+
+    pub(crate) fn step(&mut self, at: Instant) {
+        if let Some(last) = self.last_step {
+            let interval = at.saturating_duration_since(last);
+            self.min_interval = self.min_interval.min(interval);
+            self.max_interval = self.max_interval.max(interval);
+            self.total += interval;
+        }
+        if self.lateness > LATE_MARGIN {
+            self.late += 1;
+        }
+        self.worst_lateness = self.worst_lateness.max(self.lateness);
+
+        self.steps += 1;
+        self.last_step = Some(at);
+        self.lateness = Duration::from_micros(0);
+    }
+}
+
+impl Default for TimingStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Timing of the move in progress, or of the last one once it's over.
+    pub fn timing_stats(&self) -> &TimingStats {
+        &self.stats
+    }
+
+    /// Sends the stats of the finished move to the remote logger.
+    pub(crate) fn report_timing(&self) {
+        let stats = &self.stats;
+        if stats.steps < 2 {
+            return;
+        }
+
+        rlogf!(
+            "{} steps {}/{}/{}us late {} worst {}us",
+            stats.steps,
+            stats.min_interval.as_micros(),
+            stats.mean_interval().as_micros(),
+            stats.max_interval.as_micros(),
+            stats.late,
+            stats.worst_lateness.as_micros()
+        );
+    }
+}
@@ -0,0 +1,509 @@
+use embassy_futures::block_on;
+use embassy_time::{Duration, Instant};
+
+use super::mock::{Line, MockInput, PinEvent, Recorder, RecordingPin};
+use super::{
+    Axes, Direction, Driver, EncoderCount, EncoderMode, EncoderParams, HomingParams, IdlePolicy, Microsteps, MotionControl, Motor,
+    MotorParams, Profile, StateError, Ticks, TrapezoidalProfile, TravelLimits, TurnSteps,
+};
+
+// long enough for the host scheduler not to matter much
+const TICK: u64 = 1_000;
+const SLACK: Duration = Duration::from_micros(100);
+
+fn motor(recorder: &Recorder) -> Motor<RecordingPin<'_>, MockInput> {
+    Motor::from_outputs(
+        MotorParams::default().with_max_speed(TICK),
+        recorder.pin(Line::Step, false),
+        recorder.pin(Line::Dir, false),
+        recorder.pin(Line::Sleep, true),
+    )
+}
+
+fn events(recorder: &Recorder, line: Line) -> Vec<PinEvent> {
+    recorder.events().iter().filter(|e| e.line == line).copied().collect()
+}
+
+fn rising(recorder: &Recorder) -> Vec<Instant> {
+    events(recorder, Line::Step).iter().filter(|e| e.high).map(|e| e.at).collect()
+}
+
+#[test]
+fn turn_steps_pulse_the_step_pin() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    block_on(motor.start_turning(TurnSteps(5), Ticks(3))).unwrap();
+
+    let steps = events(&recorder, Line::Step);
+    assert_eq!(steps.len(), 10);
+    for (i, event) in steps.iter().enumerate() {
+        assert_eq!(event.high, i % 2 == 0);
+    }
+    assert_eq!(recorder.rising_edges(Line::Step), 5);
+    assert_eq!(motor.position(), 5);
+}
+
+#[test]
+fn ticks_leave_all_but_one_tick_between_steps() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    block_on(motor.start_turning(TurnSteps(5), Ticks(3))).unwrap();
+
+    let period = Duration::from_micros(2 * TICK);
+    let rising = rising(&recorder);
+    for pair in rising.windows(2) {
+        assert!(pair[1] - pair[0] + SLACK >= period);
+    }
+    // lateness of one step is taken off the next sleep, so the train as a whole stays on time
+    let total = rising[4] - rising[0];
+    assert!(total + SLACK >= period * 4);
+    assert!(total < period * 4 + Duration::from_millis(5));
+}
+
+#[test]
+fn single_tick_is_too_fast() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    // it would step without ever sleeping, past `max_speed` and starving every other task
+    let result = block_on(motor.start_turning(TurnSteps(20), Ticks(1)));
+
+    assert!(matches!(result, Err(StateError::TooFast { limit, .. }) if limit == 1_000_000.0 / TICK as f32));
+    assert!(recorder.events().is_empty());
+}
+
+#[test]
+fn zero_ticks_is_refused() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    let result = block_on(motor.start_turning(TurnSteps(5), Ticks(0)));
+
+    assert_eq!(result, Err(StateError::NeedMoreTicksPerStep));
+    assert!(recorder.events().is_empty());
+}
+
+#[test]
+fn velocity_profile_steps_once_per_interval() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    // 2 ms per step
+    block_on(motor.start_profiled(TurnSteps(4), Profile::Velocity(500.0))).unwrap();
+
+    let period = Duration::from_micros(2 * TICK);
+    let rising = rising(&recorder);
+    assert_eq!(rising.len(), 4);
+    let total = rising[3] - rising[0];
+    assert!(total + SLACK >= period * 3);
+    assert!(total < period * 3 + Duration::from_millis(5));
+}
+
+#[test]
+fn backward_move_sets_dir_before_the_first_step() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    block_on(motor.move_by(-3, Ticks(2))).unwrap();
+
+    let all = recorder.events();
+    assert_eq!(all[0].line, Line::Dir);
+    assert!(all[0].high);
+    assert_eq!(recorder.rising_edges(Line::Step), 3);
+    assert_eq!(motor.position(), -3);
+}
+
+#[test]
+fn stop_sent_before_a_speed_change_still_stops() {
+    static CONTROL: MotionControl = MotionControl::new();
+
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+    motor.control = Some(&CONTROL);
+
+    CONTROL.stop();
+    CONTROL.set_speed(100.0).unwrap();
+    block_on(motor.start_turning(TurnSteps(10), Ticks(2))).unwrap();
+
+    assert_eq!(recorder.rising_edges(Line::Step), 0);
+    assert_eq!(motor.position(), 0);
+}
+
+#[test]
+fn driver_sleeps_after_the_last_step() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+
+    block_on(motor.start_turning(TurnSteps(2), Ticks(2))).unwrap();
+
+    let all = recorder.events();
+    let last = all.last().unwrap();
+    assert_eq!(last.line, Line::Sleep);
+    assert!(!last.high);
+}
+
+fn microstepping(recorder: &Recorder, microsteps: Microsteps) -> Motor<RecordingPin<'_>, MockInput> {
+    let mut motor = motor(recorder);
+    motor.params = motor.params.with_microsteps(Driver::Drv8825, microsteps).unwrap();
+    motor
+}
+
+#[test]
+fn microsteps_the_driver_cant_do_are_refused() {
+    assert_eq!(
+        MotorParams::default().with_microsteps(Driver::A4988, Microsteps::ThirtySecond),
+        Err(StateError::UnsupportedMicrosteps)
+    );
+    assert!(MotorParams::default().with_microsteps(Driver::Tmc2209, Microsteps::ThirtySecond).is_ok());
+
+    let recorder = Recorder::new();
+    let mut motor = microstepping(&recorder, Microsteps::Full);
+    motor.params.driver = Driver::A4988;
+    assert_eq!(motor.set_microsteps(Microsteps::ThirtySecond), Err(StateError::UnsupportedMicrosteps));
+}
+
+#[test]
+fn microstep_pins_are_refused_for_a_tmc2209() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder);
+    motor.params = motor.params.with_microsteps(Driver::Tmc2209, Microsteps::Sixteenth).unwrap();
+
+    let pins = [
+        recorder.pin(Line::Step, false),
+        recorder.pin(Line::Step, false),
+        recorder.pin(Line::Step, false),
+    ];
+    assert!(matches!(motor.with_microstep_pins(pins), Err(StateError::UnsupportedMicrosteps)));
+}
+
+#[test]
+fn finer_microsteps_rescale_everything_in_steps() {
+    static COUNT: EncoderCount = EncoderCount::new();
+
+    let recorder = Recorder::new();
+    let mut motor = microstepping(&recorder, Microsteps::Full)
+        .with_travel_limits(TravelLimits::new(-5, 100, false))
+        .with_backlash(2)
+        .with_encoder(&COUNT, EncoderParams::new(4.0, 2, EncoderMode::Monitor));
+    motor.set_home_offset(3);
+    motor.set_position(10);
+
+    motor.set_microsteps(Microsteps::Sixteenth).unwrap();
+
+    assert_eq!(motor.position(), 160);
+    assert_eq!(motor.travel_limits, Some(TravelLimits::new(-80, 1600, false)));
+    assert_eq!(motor.home_offset, 48);
+    assert_eq!(motor.backlash(), 32);
+    assert_eq!(motor.encoder.unwrap().1.counts_per_step, 0.25);
+    assert_eq!(motor.measured_position(), Some(160));
+    assert_eq!(COUNT.get(), 40);
+}
+
+#[test]
+fn coarser_microsteps_round_the_settings() {
+    let recorder = Recorder::new();
+    let mut motor = microstepping(&recorder, Microsteps::Sixteenth)
+        .with_travel_limits(TravelLimits::new(-81, 1601, true))
+        .with_backlash(17);
+    motor.set_home_offset(-25);
+    motor.set_position(32);
+
+    motor.set_microsteps(Microsteps::Full).unwrap();
+
+    assert_eq!(motor.position(), 2);
+    // inward, so the limits never allow more travel than before
+    assert_eq!(motor.travel_limits, Some(TravelLimits::new(-5, 100, true)));
+    assert_eq!(motor.home_offset, -2);
+    assert_eq!(motor.backlash(), 2);
+}
+
+#[test]
+fn coarser_microsteps_need_a_position_on_a_step() {
+    let recorder = Recorder::new();
+    let mut motor = microstepping(&recorder, Microsteps::Eighth);
+    motor.set_position(-12);
+
+    assert_eq!(motor.set_microsteps(Microsteps::Full), Err(StateError::UnalignedPosition));
+    assert_eq!(motor.params.microsteps, Microsteps::Eighth);
+    assert_eq!(motor.position(), -12);
+
+    motor.set_microsteps(Microsteps::Half).unwrap();
+    assert_eq!(motor.position(), -3);
+}
+
+#[test]
+fn backlash_is_taken_up_when_a_move_reverses() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_backlash(3);
+
+    // the first move only learns which side the play is on
+    block_on(motor.move_by(2, Ticks(2))).unwrap();
+    assert_eq!(recorder.rising_edges(Line::Step), 2);
+
+    block_on(motor.move_by(-2, Ticks(2))).unwrap();
+    assert_eq!(recorder.rising_edges(Line::Step), 7);
+    assert_eq!(motor.position(), 0);
+    // the play was stepped through, so it shows in the timing of the move
+    assert_eq!(motor.timing_stats().steps, 5);
+}
+
+#[test]
+fn backlash_take_up_stops_at_an_end_stop() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_backlash(5).with_end_stops(Some(MockInput::new(2)), None, true);
+
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    let result = block_on(motor.move_by(-3, Ticks(2)));
+
+    assert_eq!(result, Err(StateError::EndStopTriggered));
+    assert_eq!(recorder.rising_edges(Line::Step), 3);
+    assert_eq!(motor.position(), 1);
+}
+
+#[test]
+fn stop_cuts_the_backlash_take_up_short() {
+    static CONTROL: MotionControl = MotionControl::new();
+
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_backlash(5);
+    motor.control = Some(&CONTROL);
+
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    CONTROL.stop();
+    block_on(motor.move_by(-3, Ticks(2))).unwrap();
+
+    assert_eq!(recorder.rising_edges(Line::Step), 1);
+    assert_eq!(motor.position(), 1);
+}
+
+#[test]
+fn play_left_by_a_stop_is_taken_up_by_the_next_move() {
+    static CONTROL: MotionControl = MotionControl::new();
+
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_backlash(5);
+    motor.control = Some(&CONTROL);
+
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    CONTROL.stop();
+    block_on(motor.move_by(-3, Ticks(2))).unwrap();
+
+    // none of the play was taken up, so the next move this way owes all of it
+    block_on(motor.move_by(-1, Ticks(2))).unwrap();
+    assert_eq!(recorder.rising_edges(Line::Step), 1 + 5 + 1);
+    assert_eq!(motor.position(), 0);
+
+    // and turning back after that is a full reversal again
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    assert_eq!(recorder.rising_edges(Line::Step), 7 + 5 + 1);
+}
+
+#[test]
+fn turning_back_midway_undoes_only_the_play_taken() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_backlash(5).with_end_stops(Some(MockInput::new(2)), None, true);
+
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    assert_eq!(block_on(motor.move_by(-3, Ticks(2))), Err(StateError::EndStopTriggered));
+    assert_eq!(recorder.rising_edges(Line::Step), 3);
+
+    // two steps of play were taken towards the stop, going forward again only has to undo those
+    block_on(motor.move_by(1, Ticks(2))).unwrap();
+    assert_eq!(recorder.rising_edges(Line::Step), 3 + 2 + 1);
+    assert_eq!(motor.position(), 2);
+}
+
+#[test]
+fn moves_past_the_travel_limits_are_refused() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_travel_limits(TravelLimits::new(-5, 5, false));
+
+    assert_eq!(block_on(motor.move_by(6, Ticks(2))), Err(StateError::OutOfTravel));
+    assert_eq!(block_on(motor.move_to(-6, Ticks(2))), Err(StateError::OutOfTravel));
+    assert_eq!(recorder.rising_edges(Line::Step), 0);
+
+    // right up to the limit is still fine
+    assert_eq!(block_on(motor.move_by(5, Ticks(2))), Ok(5));
+}
+
+#[test]
+fn clamped_travel_limits_shorten_the_move() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_travel_limits(TravelLimits::new(-5, 5, true));
+
+    assert_eq!(block_on(motor.move_by(8, Ticks(2))), Ok(5));
+    assert_eq!(recorder.rising_edges(Line::Step), 5);
+
+    // already at the limit, nothing left to move
+    assert_eq!(block_on(motor.move_by(3, Ticks(2))), Ok(5));
+    assert_eq!(recorder.rising_edges(Line::Step), 5);
+
+    assert_eq!(block_on(motor.move_to(-9, Ticks(2))), Ok(-5));
+    assert_eq!(recorder.rising_edges(Line::Step), 5 + 10);
+}
+
+#[test]
+fn end_stop_ends_a_move_heading_into_it() {
+    let recorder = Recorder::new();
+    let mut motor = motor(&recorder).with_end_stops(None, Some(MockInput::new(3)), true);
+
+    assert_eq!(block_on(motor.move_by(10, Ticks(2))), Err(StateError::EndStopTriggered));
+    assert_eq!(recorder.rising_edges(Line::Step), 3);
+    assert_eq!(motor.position(), 3);
+
+    // the tripped stop only guards its own side, backing off works
+    assert_eq!(block_on(motor.move_by(-2, Ticks(2))), Ok(1));
+}
+
+/// Homes backwards onto an active high switch, backing off 3 steps and giving up after 20.
+fn homing(recorder: &Recorder, switch: MockInput) -> Motor<RecordingPin<'_>, MockInput> {
+    let mut motor = motor(recorder).with_home_switch(switch, HomingParams::new(Direction::Backward, true, Ticks(2), Ticks(3), 3, 20));
+    motor.home_offset = 7;
+    motor
+}
+
+#[test]
+fn homing_backs_off_the_switch_and_finds_it_again() {
+    let recorder = Recorder::new();
+    // one read before every step and one after each seek, none while backing off
+    let mut motor = homing(&recorder, MockInput::toggling(&[4, 6, 9]));
+
+    assert_eq!(block_on(motor.home()), Ok(7));
+    assert_eq!(recorder.rising_edges(Line::Step), 4 + 3 + 2);
+    assert_eq!(motor.position(), 7);
+}
+
+#[test]
+fn homing_gives_up_after_max_travel() {
+    let recorder = Recorder::new();
+    let mut motor = homing(&recorder, MockInput::toggling(&[]));
+
+    assert_eq!(block_on(motor.home()), Err(StateError::HomeNotFound));
+    assert_eq!(recorder.rising_edges(Line::Step), 20);
+    assert_eq!(motor.position(), -20);
+}
+
+#[test]
+fn homing_starts_on_an_active_switch() {
+    let recorder = Recorder::new();
+    let mut motor = homing(&recorder, MockInput::toggling(&[0, 2, 4]));
+
+    assert_eq!(block_on(motor.home()), Ok(7));
+    assert_eq!(recorder.rising_edges(Line::Step), 3 + 1);
+}
+
+#[test]
+fn homing_refuses_a_switch_that_stays_active() {
+    let recorder = Recorder::new();
+    let mut motor = homing(&recorder, MockInput::new(0));
+
+    assert_eq!(block_on(motor.home()), Err(StateError::HomeSwitchStuck));
+    assert_eq!(recorder.rising_edges(Line::Step), 3);
+    assert_eq!(motor.position(), 3);
+}
+
+#[test]
+fn axes_take_up_backlash_together() {
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let mut axes = Axes::new([motor(&first).with_backlash(3), motor(&second).with_backlash(3)]);
+    let profile = TrapezoidalProfile::new(500.0, 1000.0, 1000.0);
+
+    block_on(axes.move_to([2, 2], &profile)).unwrap();
+    block_on(axes.move_to([1, 1], &profile)).unwrap();
+
+    assert_eq!(first.rising_edges(Line::Step), 6);
+    assert_eq!(second.rising_edges(Line::Step), 6);
+    // the second axis starts on its play before the first one is through with its own
+    assert!(rising(&second)[2] < rising(&first)[3]);
+    assert_eq!(axes.position(), [1, 1]);
+}
+
+#[test]
+fn axes_keep_timing_stats_per_motor() {
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let mut axes = Axes::new([motor(&first), motor(&second)]);
+
+    block_on(axes.move_to([4, -2], &TrapezoidalProfile::new(500.0, 1000.0, 1000.0))).unwrap();
+
+    assert_eq!(axes.motors[0].timing_stats().steps, 4);
+    assert_eq!(axes.motors[1].timing_stats().steps, 2);
+    assert!(!axes.stopped());
+}
+
+#[test]
+fn axes_stop_when_any_motor_is_told_to() {
+    static CONTROL: MotionControl = MotionControl::new();
+
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let mut axes = Axes::new([motor(&first), motor(&second)]);
+    axes.motors[1].control = Some(&CONTROL);
+
+    CONTROL.quick_stop();
+    block_on(axes.move_to([4, 4], &TrapezoidalProfile::new(500.0, 1000.0, 1000.0))).unwrap();
+
+    assert!(axes.stopped());
+    assert_eq!(first.rising_edges(Line::Step), 0);
+    assert_eq!(axes.position(), [0, 0]);
+}
+
+#[test]
+fn axes_check_the_encoder() {
+    static COUNT: EncoderCount = EncoderCount::new();
+
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let stalling = motor(&second).with_encoder(&COUNT, EncoderParams::new(1.0, 2, EncoderMode::Monitor));
+    let mut axes = Axes::new([motor(&first), stalling]);
+
+    // nothing turns the encoder, so the second axis falls behind by a step every step
+    let result = block_on(axes.move_to([10, 10], &TrapezoidalProfile::new(500.0, 1000.0, 1000.0)));
+
+    assert_eq!(result, Err(StateError::Stalled(3)));
+    assert_eq!(second.rising_edges(Line::Step), 3);
+}
+
+#[test]
+fn axes_sleep_held_drivers_once_the_hold_runs_out() {
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let hold = Duration::from_millis(5);
+    let mut axes = Axes::new([
+        motor(&first).with_idle_policy(IdlePolicy::Hold(hold), Duration::from_micros(0)),
+        motor(&second).with_idle_policy(IdlePolicy::Hold(hold * 2), Duration::from_micros(0)),
+    ]);
+
+    block_on(axes.move_to([2, 2], &TrapezoidalProfile::new(500.0, 1000.0, 1000.0))).unwrap();
+    let stopped = Instant::now();
+    assert!(!axes.motors[0].poll_idle());
+
+    block_on(axes.idle());
+
+    assert!(stopped.elapsed() + SLACK >= hold * 2);
+    assert!(axes.motors[0].poll_idle());
+    assert!(axes.motors[1].poll_idle());
+    assert!(axes.motors.iter().all(|m| m.sleep_deadline().is_none()));
+}
+
+#[test]
+fn axes_that_sit_a_move_out_ignore_their_end_stop() {
+    let first = Recorder::new();
+    let second = Recorder::new();
+    let mut axes = Axes::new([
+        motor(&first),
+        // parked on its max stop
+        motor(&second).with_end_stops(None, Some(MockInput::new(0)), true),
+    ]);
+    let profile = TrapezoidalProfile::new(500.0, 1000.0, 1000.0);
+
+    block_on(axes.move_to([3, 0], &profile)).unwrap();
+    assert_eq!(axes.position(), [3, 0]);
+    assert!(events(&second, Line::Dir).is_empty());
+
+    assert_eq!(block_on(axes.move_to([4, 1], &profile)), Err(StateError::EndStopTriggered));
+    assert_eq!(axes.position(), [3, 0]);
+}
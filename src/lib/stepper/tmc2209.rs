@@ -0,0 +1,409 @@
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, ReadExactError, Write};
+
+use super::microstep::Microsteps;
+use super::MotorParams;
+
+const SYNC: u8 = 0x05;
+/// Address byte of every reply, the master address.
+const REPLY_ADDRESS: u8 = 0xFF;
+const WRITE: u8 = 0x80;
+const REPLY_TIMEOUT: Duration = Duration::from_millis(10);
+
+pub const GCONF: u8 = 0x00;
+pub const GSTAT: u8 = 0x01;
+pub const IFCNT: u8 = 0x02;
+pub const IOIN: u8 = 0x06;
+pub const IHOLD_IRUN: u8 = 0x10;
+pub const TPWMTHRS: u8 = 0x13;
+pub const TCOOLTHRS: u8 = 0x14;
+pub const SGTHRS: u8 = 0x40;
+pub const SG_RESULT: u8 = 0x41;
+pub const CHOPCONF: u8 = 0x6C;
+pub const DRV_STATUS: u8 = 0x6F;
+pub const PWMCONF: u8 = 0x70;
+
+const GCONF_EN_SPREADCYCLE: u32 = 1 << 2;
+/// PDN_UART works as UART only with this set, otherwise it's the power down input.
+const GCONF_PDN_DISABLE: u32 = 1 << 6;
+/// Microsteps come from CHOPCONF.MRES instead of the MS1/MS2 pins (which are the address then).
+const GCONF_MSTEP_REG_SELECT: u32 = 1 << 7;
+const GCONF_MULTISTEP_FILT: u32 = 1 << 8;
+
+const CHOPCONF_MRES_SHIFT: u32 = 24;
+const CHOPCONF_MRES_MASK: u32 = 0xF << CHOPCONF_MRES_SHIFT;
+const CHOPCONF_TBL_SHIFT: u32 = 15;
+const CHOPCONF_VSENSE: u32 = 1 << 17;
+/// Power-on CHOPCONF (TOFF=3, HSTRT=5, interpolation to 256 microsteps, 0x1000_0053) with TBL raised
+/// from 0 to 2, the comparator blank time the datasheet recommends.
+const CHOPCONF_DEFAULT: u32 = 0x1000_0053 | 2 << CHOPCONF_TBL_SHIFT;
+
+#[derive(Debug)]
+pub enum TmcError<E> {
+    Io(E),
+    Timeout,
+    /// Reply CRC didn't match its contents.
+    Crc,
+    /// Reply was cut short or came from a different register than asked for.
+    BadReply,
+}
+
+/// Register setup `Tmc2209::configure` writes, currents are CS values (0..=31) of the full scale current.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TmcConfig {
+    pub run_current: u8,
+    pub hold_current: u8,
+    /// Ramp-down time from run to hold current, in units of 2^18 clocks.
+    pub hold_delay: u8,
+    /// StealthChop below `stealth_threshold`, SpreadCycle otherwise.
+    pub stealth_chop: bool,
+    /// TPWMTHRS, step interval in clocks under which the driver switches to SpreadCycle, 0 never switches.
+    pub stealth_threshold: u32,
+    /// TCOOLTHRS, StallGuard and the DIAG output are active for step intervals under this.
+    pub coolstep_threshold: u32,
+    /// SGTHRS, a stall is flagged when SG_RESULT drops below twice this.
+    pub stall_threshold: u8,
+    /// Lower sense voltage range, for small motors on the default sense resistors.
+    pub vsense: bool,
+}
+
+impl TmcConfig {
+    pub fn new(run_current: u8, hold_current: u8) -> Self {
+        Self {
+            run_current: run_current.min(31),
+            hold_current: hold_current.min(31),
+            ..Default::default()
+        }
+    }
+
+    /// Picks `run_current` for an RMS current in mA with the board's sense resistors (in ohms).
+    pub fn with_run_current_ma(mut self, milliamps: u32, r_sense: f32) -> Self {
+        self.run_current = current_scale(milliamps, r_sense, self.vsense);
+        self
+    }
+
+    pub fn with_hold_current_ma(mut self, milliamps: u32, r_sense: f32) -> Self {
+        self.hold_current = current_scale(milliamps, r_sense, self.vsense);
+        self
+    }
+}
+
+impl Default for TmcConfig {
+    fn default() -> Self {
+        Self {
+            run_current: 16,
+            hold_current: 8,
+            hold_delay: 1,
+            stealth_chop: true,
+            stealth_threshold: 0,
+            coolstep_threshold: 0,
+            stall_threshold: 0,
+            vsense: false,
+        }
+    }
+}
+
+/// One TMC2209 on a UART, up to four of them share a line with addresses 0..=3 set by MS1/MS2.
+///
+/// The single wire interface echoes every byte sent, with `echo` those are read back and dropped.
+pub struct Tmc2209<U: Read + Write> {
+    uart: U,
+    address: u8,
+    echo: bool,
+    /// Last value written to TCOOLTHRS, the register can't be read back.
+    coolstep_threshold: u32,
+}
+
+impl<U: Read + Write> Tmc2209<U> {
+    pub fn new(uart: U, address: u8, echo: bool) -> Self {
+        Self {
+            uart,
+            address: address & 0x03,
+            echo,
+            coolstep_threshold: 0,
+        }
+    }
+
+    pub fn release(self) -> U {
+        self.uart
+    }
+
+    pub async fn write_register(&mut self, register: u8, value: u32) -> Result<(), TmcError<U::Error>> {
+        let frame = write_frame(self.address, register, value);
+        self.send(&frame).await?;
+        if register == TCOOLTHRS {
+            self.coolstep_threshold = value;
+        }
+        // the driver needs a few bit times before it listens again
+        Timer::after(Duration::from_micros(100)).await;
+        Ok(())
+    }
+
+    pub async fn read_register(&mut self, register: u8) -> Result<u32, TmcError<U::Error>> {
+        let request = read_request(self.address, register);
+        self.send(&request).await?;
+
+        let mut reply = [0u8; 8];
+        self.receive(&mut reply).await?;
+        parse_reply(&reply, register).map_err(|e| match e {
+            FrameError::Crc => TmcError::Crc,
+            FrameError::BadReply => TmcError::BadReply,
+        })
+    }
+
+    /// Write, then check the interface counter went up, writes get no reply otherwise.
+    pub async fn write_verified(&mut self, register: u8, value: u32) -> Result<(), TmcError<U::Error>> {
+        let before = self.read_register(IFCNT).await?;
+        self.write_register(register, value).await?;
+        let after = self.read_register(IFCNT).await?;
+
+        if after & 0xFF != (before + 1) & 0xFF {
+            return Err(TmcError::BadReply);
+        }
+        Ok(())
+    }
+
+    /// Puts the driver under UART control and sets currents, chopper modes and the resolution of `params`.
+    pub async fn configure(&mut self, params: &MotorParams, config: &TmcConfig) -> Result<(), TmcError<U::Error>> {
+        let mut gconf = GCONF_PDN_DISABLE | GCONF_MSTEP_REG_SELECT | GCONF_MULTISTEP_FILT;
+        if !config.stealth_chop {
+            gconf |= GCONF_EN_SPREADCYCLE;
+        }
+        self.write_verified(GCONF, gconf).await?;
+
+        let mut chopconf = with_mres(CHOPCONF_DEFAULT, params.microsteps);
+        if config.vsense {
+            chopconf |= CHOPCONF_VSENSE;
+        }
+        self.write_verified(CHOPCONF, chopconf).await?;
+
+        self.write_verified(IHOLD_IRUN, ihold_irun(config.hold_current, config.run_current, config.hold_delay)).await?;
+        self.write_verified(TPWMTHRS, config.stealth_threshold & 0xF_FFFF).await?;
+        self.write_verified(TCOOLTHRS, config.coolstep_threshold & 0xF_FFFF).await?;
+        self.write_verified(SGTHRS, config.stall_threshold as u32).await
+    }
+
+    /// Changes the resolution only, keep `MotorParams` in line (`Motor::set_microsteps`).
+    pub async fn set_microsteps(&mut self, microsteps: Microsteps) -> Result<(), TmcError<U::Error>> {
+        let chopconf = self.read_register(CHOPCONF).await?;
+        self.write_verified(CHOPCONF, with_mres(chopconf, microsteps)).await
+    }
+
+    pub async fn set_current(&mut self, run_current: u8, hold_current: u8, hold_delay: u8) -> Result<(), TmcError<U::Error>> {
+        self.write_verified(IHOLD_IRUN, ihold_irun(hold_current, run_current, hold_delay)).await
+    }
+
+    pub async fn set_stall_threshold(&mut self, threshold: u8) -> Result<(), TmcError<U::Error>> {
+        self.write_verified(SGTHRS, threshold as u32).await
+    }
+
+    /// TCOOLTHRS as last written through this driver (0, the power-on value, before that).
+    pub fn coolstep_threshold(&self) -> u32 {
+        self.coolstep_threshold
+    }
+
+    /// StallGuard load value, lower means more load, 0 is (close to) a stall.
+    pub async fn stall_guard_result(&mut self) -> Result<u16, TmcError<U::Error>> {
+        Ok((self.read_register(SG_RESULT).await? & 0x3FF) as u16)
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> Result<(), TmcError<U::Error>> {
+        self.uart.write_all(frame).await.map_err(TmcError::Io)?;
+        self.uart.flush().await.map_err(TmcError::Io)?;
+
+        if self.echo {
+            let mut echo = [0u8; 8];
+            self.receive(&mut echo[..frame.len()]).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<(), TmcError<U::Error>> {
+        match with_timeout(REPLY_TIMEOUT, self.uart.read_exact(buf)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(ReadExactError::UnexpectedEof)) => Err(TmcError::BadReply),
+            Ok(Err(ReadExactError::Other(e))) => Err(TmcError::Io(e)),
+            Err(_) => Err(TmcError::Timeout),
+        }
+    }
+}
+
+/// What's wrong with a reply frame, before it turns into a `TmcError`.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub(crate) enum FrameError {
+    Crc,
+    BadReply,
+}
+
+/// CRC8 of the TMC UART datagrams, polynomial x^8 + x^2 + x + 1 fed LSB first.
+pub(crate) fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        let mut byte = byte;
+        for _ in 0..8 {
+            if (crc >> 7) ^ (byte & 0x01) != 0 {
+                crc = (crc << 1) ^ 0x07;
+            } else {
+                crc <<= 1;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+pub(crate) fn write_frame(address: u8, register: u8, value: u32) -> [u8; 8] {
+    let data = value.to_be_bytes();
+    let mut frame = [SYNC, address, register | WRITE, data[0], data[1], data[2], data[3], 0];
+    frame[7] = crc8(&frame[..7]);
+    frame
+}
+
+pub(crate) fn read_request(address: u8, register: u8) -> [u8; 4] {
+    let mut frame = [SYNC, address, register & !WRITE, 0];
+    frame[3] = crc8(&frame[..3]);
+    frame
+}
+
+pub(crate) fn parse_reply(reply: &[u8; 8], register: u8) -> Result<u32, FrameError> {
+    if crc8(&reply[..7]) != reply[7] {
+        return Err(FrameError::Crc);
+    }
+    if reply[0] & 0x0F != SYNC || reply[1] != REPLY_ADDRESS || reply[2] != register & !WRITE {
+        return Err(FrameError::BadReply);
+    }
+    Ok(u32::from_be_bytes([reply[3], reply[4], reply[5], reply[6]]))
+}
+
+fn ihold_irun(hold: u8, run: u8, delay: u8) -> u32 {
+    (hold.min(31) as u32) | (run.min(31) as u32) << 8 | (delay.min(15) as u32) << 16
+}
+
+/// MRES counts down from 256 microsteps (0) to full steps (8).
+fn with_mres(chopconf: u32, microsteps: Microsteps) -> u32 {
+    let mres = 8 - microsteps.factor().trailing_zeros();
+    (chopconf & !CHOPCONF_MRES_MASK) | mres << CHOPCONF_MRES_SHIFT
+}
+
+/// CS value for an RMS current, I = (CS + 1) / 32 * Vfs / (Rsense + 20mOhm) / sqrt(2).
+fn current_scale(milliamps: u32, r_sense: f32, vsense: bool) -> u8 {
+    let v_fs = if vsense { 0.180 } else { 0.325 };
+    let cs = 32.0 * 1.414_213_6 * milliamps as f32 / 1000.0 * (r_sense + 0.02) / v_fs - 1.0;
+    if cs < 0.0 {
+        0
+    } else {
+        (cs as u32).min(31) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(register: u8, value: u32) -> [u8; 8] {
+        let data = value.to_be_bytes();
+        let mut reply = [SYNC, REPLY_ADDRESS, register, data[0], data[1], data[2], data[3], 0];
+        reply[7] = crc8(&reply[..7]);
+        reply
+    }
+
+    #[test]
+    fn crc_of_a_gconf_read() {
+        assert_eq!(crc8(&[0x05, 0x00, 0x00]), 0x48);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn read_request_layout() {
+        let request = read_request(2, CHOPCONF);
+        assert_eq!(request[..3], [SYNC, 2, CHOPCONF]);
+        assert_eq!(request[3], crc8(&request[..3]));
+        assert_eq!(read_request(0, GCONF), [0x05, 0x00, 0x00, 0x48]);
+    }
+
+    #[test]
+    fn read_request_clears_the_write_bit() {
+        assert_eq!(read_request(0, GCONF | WRITE)[2], GCONF);
+    }
+
+    #[test]
+    fn write_frame_layout() {
+        let frame = write_frame(3, IHOLD_IRUN, 0x0001_1008);
+        assert_eq!(frame[..7], [SYNC, 3, IHOLD_IRUN | WRITE, 0x00, 0x01, 0x10, 0x08]);
+        assert_eq!(frame[7], crc8(&frame[..7]));
+    }
+
+    #[test]
+    fn parses_a_reply() {
+        assert_eq!(parse_reply(&reply(IFCNT, 0x0000_002A), IFCNT), Ok(0x2A));
+        assert_eq!(parse_reply(&reply(CHOPCONF, 0x1001_0053), CHOPCONF), Ok(0x1001_0053));
+    }
+
+    #[test]
+    fn reply_with_bad_crc() {
+        let mut bad = reply(IFCNT, 7);
+        bad[7] ^= 0x01;
+        assert_eq!(parse_reply(&bad, IFCNT), Err(FrameError::Crc));
+
+        let mut flipped = reply(IFCNT, 7);
+        flipped[6] ^= 0x80;
+        assert_eq!(parse_reply(&flipped, IFCNT), Err(FrameError::Crc));
+    }
+
+    #[test]
+    fn reply_from_a_wrong_address_or_register() {
+        let mut from_slave = reply(IFCNT, 7);
+        from_slave[1] = 0x00;
+        from_slave[7] = crc8(&from_slave[..7]);
+        assert_eq!(parse_reply(&from_slave, IFCNT), Err(FrameError::BadReply));
+
+        assert_eq!(parse_reply(&reply(GSTAT, 7), IFCNT), Err(FrameError::BadReply));
+    }
+
+    #[test]
+    fn reply_without_sync() {
+        let mut unsynced = reply(IFCNT, 7);
+        unsynced[0] = 0x00;
+        unsynced[7] = crc8(&unsynced[..7]);
+        assert_eq!(parse_reply(&unsynced, IFCNT), Err(FrameError::BadReply));
+    }
+
+    #[test]
+    fn mres_counts_down_from_256() {
+        assert_eq!(with_mres(0, Microsteps::Full) >> CHOPCONF_MRES_SHIFT, 8);
+        assert_eq!(with_mres(0, Microsteps::Half) >> CHOPCONF_MRES_SHIFT, 7);
+        assert_eq!(with_mres(0, Microsteps::Sixteenth) >> CHOPCONF_MRES_SHIFT, 4);
+        assert_eq!(with_mres(0, Microsteps::ThirtySecond) >> CHOPCONF_MRES_SHIFT, 3);
+    }
+
+    #[test]
+    fn mres_keeps_the_other_bits() {
+        assert_eq!(with_mres(0xFFFF_FFFF, Microsteps::Sixteenth), 0xF4FF_FFFF);
+        assert_eq!(with_mres(CHOPCONF_DEFAULT, Microsteps::Eighth), 0x1501_0053);
+    }
+
+    #[test]
+    fn default_chopper_blank_time() {
+        assert_eq!((CHOPCONF_DEFAULT >> CHOPCONF_TBL_SHIFT) & 0x3, 2);
+        assert_eq!(CHOPCONF_DEFAULT & 0xF, 3);
+    }
+
+    #[test]
+    fn current_scale_for_rms_current() {
+        // 18/32 * 0.325V / 0.13Ohm / sqrt(2) = 0.99A
+        assert_eq!(current_scale(1000, 0.11, false), 17);
+        assert_eq!(current_scale(500, 0.11, true), 15);
+    }
+
+    #[test]
+    fn current_scale_saturates() {
+        assert_eq!(current_scale(0, 0.11, false), 0);
+        assert_eq!(current_scale(10_000, 0.11, false), 31);
+    }
+
+    #[test]
+    fn hold_and_run_current_fields() {
+        assert_eq!(ihold_irun(8, 16, 1), 0x0001_1008);
+        assert_eq!(ihold_irun(40, 40, 20), 0x000F_1F1F);
+    }
+}
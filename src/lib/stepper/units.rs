@@ -0,0 +1,115 @@
+use super::pin::{DigitalIn, DigitalOut};
+use super::planner::Profile;
+use super::{Motor, MotorParams, StateError, Ticks};
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Angle {
+    Degrees(f32),
+    Revolutions(f32),
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Speed {
+    Rpm(f32),
+    DegreesPerSecond(f32),
+    StepsPerSecond(f32),
+}
+
+pub trait UnitTraits {
+    fn degrees(self) -> Angle;
+    fn revolutions(self) -> Angle;
+    fn rpm(self) -> Speed;
+    fn degrees_per_second(self) -> Speed;
+}
+
+impl UnitTraits for f32 {
+    fn degrees(self) -> Angle {
+        Angle::Degrees(self)
+    }
+
+    fn revolutions(self) -> Angle {
+        Angle::Revolutions(self)
+    }
+
+    fn rpm(self) -> Speed {
+        Speed::Rpm(self)
+    }
+
+    fn degrees_per_second(self) -> Speed {
+        Speed::DegreesPerSecond(self)
+    }
+}
+
+impl MotorParams {
+    pub fn angle_to_steps(&self, angle: Angle) -> i32 {
+        match angle {
+            Angle::Degrees(d) => self.degrees_to_steps(d),
+            Angle::Revolutions(r) => self.revolutions_to_steps(r),
+        }
+    }
+
+    pub fn steps_per_second(&self, speed: Speed) -> f32 {
+        match speed {
+            Speed::Rpm(rpm) => rpm * self.steps_per_revolution() as f32 / 60.0,
+            Speed::DegreesPerSecond(d) => d / self.step_angle(),
+            Speed::StepsPerSecond(s) => s,
+        }
+    }
+
+    /// Fastest speed allowed, one step every `max_speed` microseconds.
+    pub fn max_steps_per_second(&self) -> f32 {
+        1_000_000.0 / self.max_speed as f32
+    }
+
+    pub fn check_speed(&self, velocity: f32) -> Result<(), StateError> {
+        let limit = self.max_steps_per_second();
+        if velocity > limit {
+            return Err(StateError::TooFast { requested: velocity, limit });
+        }
+        Ok(())
+    }
+
+    /// `Ticks(n)` sleeps `n - 1` ticks of `max_speed` between steps, so it takes at least 2 to stay within the limit.
+    pub fn check_ticks(&self, ticks: Ticks) -> Result<(), StateError> {
+        match ticks.count() {
+            0 => Err(StateError::NeedMoreTicksPerStep),
+            // no sleep at all, steps as fast as the loop spins
+            1 => Err(StateError::TooFast {
+                requested: f32::INFINITY,
+                limit: self.max_steps_per_second(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_profile(&self, profile: &Profile) -> Result<(), StateError> {
+        if let Profile::Constant(ticks) = profile {
+            return self.check_ticks(*ticks);
+        }
+        match profile.max_velocity() {
+            Some(velocity) => self.check_speed(velocity),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<P: DigitalOut, I: DigitalIn> Motor<P, I> {
+    /// Turns by `angle` (negative turns backward) at a constant `speed`, returns the final position in steps.
+    pub async fn rotate(&mut self, angle: Angle, speed: Speed) -> Result<i32, StateError> {
+        let profile = self.velocity_profile(speed)?;
+        self.move_by(self.params.angle_to_steps(angle), profile).await
+    }
+
+    /// Turns to the absolute `angle` at a constant `speed`, returns the final position in steps.
+    pub async fn rotate_to(&mut self, angle: Angle, speed: Speed) -> Result<i32, StateError> {
+        let profile = self.velocity_profile(speed)?;
+        self.move_to(self.params.angle_to_steps(angle), profile).await
+    }
+
+    fn velocity_profile(&self, speed: Speed) -> Result<Profile, StateError> {
+        let velocity = self.params.steps_per_second(speed);
+        self.params.check_speed(velocity)?;
+
+        Ok(Profile::Velocity(velocity))
+    }
+}